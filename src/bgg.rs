@@ -1,30 +1,326 @@
-use failure::{Error, ResultExt, bail};
 use reqwest::Client;
 use reqwest::StatusCode;
+use reqwest::header::RETRY_AFTER;
 use select::document::Document;
 use select::predicate::{Name, Class};
-use crate::lib::{Game, User};
+use serde_derive::{Serialize, Deserialize};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::Error;
+use crate::lib::{Game, User, UserStats, Rating};
+use crate::tape::{self, Tape, Cassette};
+
+// bail! for pages that do not look as expected
+macro_rules! broken {
+    ($($arg:tt)*) => { return Err(Error::Parse(format!($($arg)*))) }
+}
 
 pub const USER_PAGE_SIZE: u32 = 100;
+pub const BASE_URL: &str = "https://boardgamegeek.com";
+
+/// How to find out average rating of a user.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum UserMethod {
+    Profile, // scrape profile page
+    Collection // xml api, profile page is a fallback
+}
+
+/// Everything the balancing needs to know from bgg.
+pub trait BggSource {
+    /// Games with at least user_limit ratings, pages start from 1.
+    fn games(&self, page: u32, user_limit: u32) -> Result<Vec<Game>, Error>;
+    /// User ratings of a game, pages start from 1.
+    fn ratings(&self, game_id: u32, page: u32) -> Result<Vec<Rating>, Error>;
+    /// Average rating given by a user.
+    fn user_stats(&self, user: &User) -> Result<UserStats, Error>;
+}
+
+pub type Source = Arc<dyn BggSource + Send + Sync>;
+
+/// What non 200 answer from bgg means for the next request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Queued, // 202, bgg prepares the answer, ask again soon
+    Throttled, // 429, ask again after Retry-After
+    Transient, // 5xx, server is in trouble, ask again later
+    Gone, // 404, e.g. deleted user, asking again is pointless
+    Rejected // any other status, something is wrong on our side
+}
+
+impl Status {
+    pub fn of(code: StatusCode) -> Option<Status> {
+        match code {
+            StatusCode::ACCEPTED => Some(Status::Queued),
+            c if c.is_success() => None,
+            StatusCode::TOO_MANY_REQUESTS => Some(Status::Throttled),
+            StatusCode::NOT_FOUND | StatusCode::GONE => Some(Status::Gone),
+            c if c.is_server_error() => Some(Status::Transient),
+            _ => Some(Status::Rejected)
+        }
+    }
+}
+
+/// Number of extra requests and pause between them, ms.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct Retry {
+    pub tries: u32,
+    pub delay: u32
+}
+
+/// How to retry every kind of status that is worth retrying.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(from = "PartialPolicy")]
+pub struct RetryPolicy {
+    pub queued: Retry, // fixed delay
+    pub throttled: Retry, // Retry-After if bgg sends it, delay otherwise
+    pub transient: Retry, // delay doubles after every try, network errors too
+    pub max_retry_after: u32 // ms, longer Retry-After from bgg is cut down to it
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            queued: Retry { tries: 5, delay: 3000 },
+            throttled: Retry { tries: 3, delay: 30000 },
+            transient: Retry { tries: 3, delay: 5000 },
+            max_retry_after: 300000
+        }
+    }
+}
+
+// retry policy as written in config, missing keys come from the default one
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PartialPolicy {
+    queued: PartialRetry,
+    throttled: PartialRetry,
+    transient: PartialRetry,
+    max_retry_after: Option<u32>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PartialRetry {
+    tries: Option<u32>,
+    delay: Option<u32>
+}
+
+impl PartialRetry {
+    fn or(self, retry: Retry) -> Retry {
+        Retry { tries: self.tries.unwrap_or(retry.tries), delay: self.delay.unwrap_or(retry.delay) }
+    }
+}
+
+impl From<PartialPolicy> for RetryPolicy {
+    fn from(p: PartialPolicy) -> RetryPolicy {
+        let d = RetryPolicy::default();
+        RetryPolicy {
+            queued: p.queued.or(d.queued),
+            throttled: p.throttled.or(d.throttled),
+            transient: p.transient.or(d.transient),
+            max_retry_after: p.max_retry_after.unwrap_or(d.max_retry_after)
+        }
+    }
+}
+
+/// Spaces requests out evenly, every thread asking bgg shares one.
+pub struct RateLimiter {
+    interval: Option<Duration>, // None if there is no limit
+    next: Mutex<Instant> // the earliest moment for the next request
+}
+
+impl RateLimiter {
+    /// per_minute of 0 means no limit.
+    pub fn new(per_minute: u32) -> RateLimiter {
+        let interval = if per_minute == 0 { None } else { Some(Duration::from_secs(60) / per_minute) };
+        RateLimiter { interval, next: Mutex::new(Instant::now()) }
+    }
+
+    /// Blocks until the request fits the budget.
+    fn wait(&self) {
+        // book a slot and sleep without holding the lock
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            if let Some(interval) = self.interval {
+                *next = slot + interval;
+            }
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+
+    /// No request from any thread before pause is over, even without a limit.
+    fn hold(&self, pause: Duration) {
+        let mut next = self.next.lock().unwrap();
+        *next = (*next).max(Instant::now() + pause);
+    }
+}
+
+pub struct Scraper {
+    client: Client,
+    base_url: String,
+    tape: Tape,
+    user_method: UserMethod,
+    retry: RetryPolicy,
+    limiter: RateLimiter
+}
+
+struct Page {
+    status: StatusCode,
+    retry_after: Option<Duration>,
+    body: String
+}
+
+impl Scraper {
+    pub fn new(base_url: &str, tape: Tape, user_method: UserMethod, retry: RetryPolicy, limiter: RateLimiter) -> Scraper {
+        Scraper {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            tape,
+            user_method,
+            retry,
+            limiter
+        }
+    }
+
+    /// Body of the page, retries according to the policy.
+    fn get(&self, path: &str) -> Result<String, Error> {
+        // cassette answers the same way every time, retry is pointless
+        let replay = matches!(self.tape, Tape::Replay(_));
+        // every class uses up its own budget only
+        let (mut queued, mut throttled, mut transient) = (0, 0, 0);
+        loop {
+            let page = match self.fetch(path) {
+                Err(e) => {
+                    if replay || transient >= self.retry.transient.tries {
+                        return Err(e);
+                    }
+                    thread::sleep(backoff(self.retry.transient, transient));
+                    transient += 1;
+                    continue;
+                },
+                Ok(page) => page
+            };
+            let status = match Status::of(page.status) {
+                None => return Ok(page.body),
+                Some(status) => status
+            };
+            let (retry, tries, delay) = match status {
+                Status::Queued => (self.retry.queued, &mut queued, ms(self.retry.queued.delay)),
+                Status::Throttled => match page.retry_after {
+                    // bgg counts requests of every thread, all of them wait
+                    Some(after) => {
+                        self.limiter.hold(after);
+                        (self.retry.throttled, &mut throttled, Duration::from_secs(0))
+                    },
+                    None => (self.retry.throttled, &mut throttled, ms(self.retry.throttled.delay))
+                },
+                Status::Transient => {
+                    let delay = backoff(self.retry.transient, transient);
+                    (self.retry.transient, &mut transient, delay)
+                },
+                Status::Gone | Status::Rejected => return Err(Error::http(path, page.status))
+            };
+            if replay || *tries >= retry.tries {
+                return Err(Error::http(path, page.status));
+            }
+            thread::sleep(delay);
+            *tries += 1;
+        }
+    }
+
+    fn fetch(&self, path: &str) -> Result<Page, Error> {
+        if let Tape::Replay(dir) = &self.tape {
+            let cassette = tape::replay(dir, path)?;
+            let status = StatusCode::from_u16(cassette.status)
+                .map_err(|e| Error::Cassette(format!("Cassette for `{}` is broken: {}", path, e)))?;
+            return Ok(Page { status, retry_after: None, body: cassette.body });
+        }
+        // retries count too, it is bgg who is counting
+        self.limiter.wait();
+        let url = format!("{}{}", self.base_url, path);
+        let mut resp = self.client.get(&url).send()
+            .map_err(|e| Error::Network { url: url.clone(), reason: e.to_string() })?;
+        let mut bytes = Vec::new();
+        resp.read_to_end(&mut bytes)
+            .map_err(|e| Error::Network { url: url.clone(), reason: e.to_string() })?;
+        let mut body = String::from_utf8_lossy(&bytes).into_owned();
+        if let Tape::Record(dir) = &self.tape {
+            let cassette = Cassette { url: path.to_string(), status: resp.status().as_u16(), body };
+            tape::record(dir, &cassette)?;
+            body = cassette.body;
+        }
+        // only delay-seconds form, http date is rare enough
+        let retry_after = resp.headers().get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.trim().parse::<u64>().ok())
+            .map(|s| Duration::from_secs(s).min(ms(self.retry.max_retry_after)));
+        Ok(Page { status: resp.status(), retry_after, body })
+    }
+}
+
+// user names can have spaces, & or +, they must stay a single url part
+fn encode(part: &str) -> String {
+    part.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => char::from(b).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
+}
+
+fn ms(delay: u32) -> Duration {
+    Duration::from_millis(u64::from(delay))
+}
+
+fn backoff(retry: Retry, tries: u32) -> Duration {
+    ms(retry.delay) * 2u32.saturating_pow(tries)
+}
+
+impl BggSource for Scraper {
+    fn games(&self, page: u32, user_limit: u32) -> Result<Vec<Game>, Error> {
+        get_games_from(self, page, user_limit)
+    }
+    fn ratings(&self, game_id: u32, page: u32) -> Result<Vec<Rating>, Error> {
+        get_users_from(self, game_id, page)
+    }
+    fn user_stats(&self, user: &User) -> Result<UserStats, Error> {
+        match self.user_method {
+            UserMethod::Profile => get_user_average_rating(self, user),
+            UserMethod::Collection => match get_user_collection(self, user) {
+                Ok(stats) => Ok(stats),
+                Err(e) if e.is_gone() => Err(e),
+                // profile could still be there, report api error if not,
+                // unless profile tells that user is gone
+                Err(e) => get_user_average_rating(self, user).map_err(|p| if p.is_gone() { p } else { e })
+            }
+        }
+    }
+}
 
 pub struct UserIterator<'a> {
-    client: &'a Client,
+    source: &'a dyn BggSource,
     game_id: u32,
     page: u32
 }
 
 impl<'a> UserIterator<'a> {
-    pub fn new(client: &'a Client, game_id: u32, page: u32) -> UserIterator {
-        UserIterator {client, game_id, page }
+    pub fn new(source: &'a dyn BggSource, game_id: u32, page: u32) -> UserIterator<'a> {
+        UserIterator {source, game_id, page }
     }
 }
 
 impl<'a> Iterator for UserIterator<'a> {
-    type Item = Result<Vec<(User, f64)>, Error>;
+    type Item = Result<Vec<Rating>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
         // get users for a game
-        let result = match get_users_from(self.client, self.game_id, self.page) {
+        let result = match self.source.ratings(self.game_id, self.page) {
             Ok(users) => {
                 if users.is_empty() {
                     None
@@ -39,50 +335,54 @@ impl<'a> Iterator for UserIterator<'a> {
     }
 }
 
-fn get_users_from(client: &Client, game_id: u32, page: u32) -> Result<Vec<(User, f64)>, Error> {
-    let url =  format!(
-        "https://www.boardgamegeek.com/xmlapi2/thing?type=boardgame&id={}&ratingcomments=1&page={}&pagesize={}",
+fn get_users_from(scraper: &Scraper, game_id: u32, page: u32) -> Result<Vec<Rating>, Error> {
+    let path =  format!(
+        "/xmlapi2/thing?type=boardgame&id={}&ratingcomments=1&page={}&pagesize={}",
         game_id,
         page,
         USER_PAGE_SIZE
     );
-    let resp = client.get(&url).send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!("Can't get page {} for {}. Status: {}", page, game_id, resp.status());
-    }
-    let doc = Document::from_read(resp)?;
+    let body = scraper.get(&path)?;
+    let doc = Document::from(body.as_str());
     filter_users(doc)
 }
 
-fn filter_users(doc: Document) -> Result<Vec<(User, f64)>, Error> {
+fn filter_users(doc: Document) -> Result<Vec<Rating>, Error> {
     let usertags = doc.find(Name("comment"));
 
     let mut users = Vec::new();
     for tag in usertags {
         let name = match tag.attr("username") {
             Some(n) => String::from(n),
-            _ => bail!("Can't parse username in the user list")
+            _ => broken!("Can't parse username in the user list")
         };
         let rating = match tag.attr("rating") {
             Some(r) => r.parse::<f64>()?,
-            _ => bail!("Can't parse user rating in the user list")
+            _ => broken!("Can't parse user rating in the user list")
         };
-        users.push((name, rating));
+        let comment = match tag.attr("value") {
+            Some(c) if !c.trim().is_empty() => Some(String::from(c)),
+            _ => None
+        };
+        users.push(Rating { user: name, rating, comment });
     }
     Ok(users)
 }
 
 pub struct GameIterator<'a> {
-    client: &'a Client,
+    source: &'a dyn BggSource,
     page: u32,
     user_limit: u32,
     seen: Option<Game>
 }
 
 impl<'a> GameIterator<'a> {
-    pub fn new(client: &'a Client, user_limit: u32) -> GameIterator {
-        GameIterator {client, page: 0 , user_limit, seen: None}
+    pub fn new(source: &'a dyn BggSource, user_limit: u32) -> GameIterator<'a> {
+        GameIterator {source, page: 0 , user_limit, seen: None}
+    }
+    /// Continues after page, seen is the first game of it.
+    pub fn resume(source: &'a dyn BggSource, user_limit: u32, page: u32, seen: Option<Game>) -> GameIterator<'a> {
+        GameIterator {source, page, user_limit, seen}
     }
 }
 
@@ -92,7 +392,7 @@ impl<'a> Iterator for GameIterator<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         self.page += 1;
         // get games from a page
-        match get_games_from(self.client, self.page, self.user_limit) {
+        match self.source.games(self.page, self.user_limit) {
             Ok(games) => {
                 if games.first() == self.seen.as_ref() || games.is_empty() {
                     None
@@ -106,18 +406,14 @@ impl<'a> Iterator for GameIterator<'a> {
     }
 }
 
-fn get_games_from(client: &Client, page: u32, user_limit: u32) -> Result<Vec<Game>, Error> {
-    let url =  format!(
-        "https://boardgamegeek.com/search/boardgame/page/{}?advsearch=1&range%5Bnumvoters%5D%5Bmin%5D={}&nosubtypes%5B0%5D=boardgameexpansion",
+fn get_games_from(scraper: &Scraper, page: u32, user_limit: u32) -> Result<Vec<Game>, Error> {
+    let path =  format!(
+        "/search/boardgame/page/{}?advsearch=1&range%5Bnumvoters%5D%5Bmin%5D={}&nosubtypes%5B0%5D=boardgameexpansion",
         page,
         user_limit
     );
-    let resp = client.get(&url).send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!("Can't get games from {}", page);
-    }
-    let doc = Document::from_read(resp)?;
+    let body = scraper.get(&path)?;
+    let doc = Document::from(body.as_str());
     filter_games(doc)
 }
 
@@ -137,35 +433,32 @@ fn filter_games(doc: Document) -> Result<Vec<Game>, Error> {
         let link = match link {
             Some(node) => match node.find(Name("a")).nth(0) {
                 Some(l) => l,
-                None => bail!("Could not find game link.")
+                None => broken!("Could not find game link.")
             },
-            None => bail!("Could not find game link.") 
+            None => broken!("Could not find game link.") 
         };
         let id = match link.attr("href") {
             Some(href) => href_to_id(href)?,
-            None => bail!("Could not find game id.")
+            None => broken!("Could not find game id.")
         };
         let bgg_geek_rating = match bgg_geek_rating{
             Some(node) => node.text().trim().parse::<f64>()?,
-            None => bail!("Could not find geek rating.")
+            None => broken!("Could not find geek rating.")
         };
         let bgg_avg_rating = match bgg_avg_rating{
             Some(node) => node.text().trim().parse::<f64>()?,
-            None => bail!("Could not find avg rating.")
+            None => broken!("Could not find avg rating.")
         };
         let bgg_num_votes = match bgg_num_votes{
             Some(node) => node.text().trim().parse::<u32>()?,
-            None => bail!("Could not find num votes.")
+            None => broken!("Could not find num votes.")
         };
 
         games.push(Game {
-            id,
-            name: link.text(),
-            rating: 0.0,
-            votes: 0,
             bgg_num_votes,
             bgg_geek_rating,
-            bgg_avg_rating
+            bgg_avg_rating,
+            ..Game::new(id, link.text())
         });
     }
     Ok(games)
@@ -175,27 +468,71 @@ fn href_to_id(href: &str) -> Result<u32, Error> {
     let parts: Vec<&str> = href.rsplit('/').take(2).collect();
     let id = match parts.get(1) {
         Some(x) => x.parse::<u32>()?,
-        None => bail!("Can't parse id of the game: {}", href)
+        None => broken!("Can't parse id of the game: {}", href)
     };
     Ok(id)
 }
 
-pub fn get_user_average_rating(client: &Client, user: &User) -> Result<f64, Error> {
-    let url =  format!("https://boardgamegeek.com/user/{}", user);
-    let resp = client.get(&url).send()
-        .with_context(|_| format!("could not download page `{}`", url))?;
-    if resp.status() != StatusCode::OK {
-        bail!("Can't get user average for {}", user);
-    }
-    let doc = Document::from_read(resp)?;
+fn get_user_average_rating(scraper: &Scraper, user: &User) -> Result<UserStats, Error> {
+    let path =  format!("/user/{}", encode(user));
+    let body = scraper.get(&path)?;
+    let doc = Document::from(body.as_str());
     let rating = doc
         .find(Class("profile_block")).skip(3).take(1)
         .flat_map(|pb| pb.find(Name("table"))).skip(5).take(1)
         .flat_map(|t| t.find(Name("tr"))).skip(2).take(1)
         .flat_map(|tr| tr.find(Name("td"))).nth(1);
     let rating = match rating {
-        None => bail!("Can't find rating element"),
+        None => broken!("Can't find rating element"),
         Some(r) => r.text().parse::<f64>()?
     };
-    Ok(rating)
+    Ok(UserStats { average: rating, ratings: None, std_dev: None })
+}
+
+fn get_user_collection(scraper: &Scraper, user: &User) -> Result<UserStats, Error> {
+    // expansions are rated along with their games, they would count twice
+    let path = format!("/xmlapi2/collection?username={}&rated=1&stats=1&subtype=boardgame&excludesubtype=boardgameexpansion",
+        encode(user));
+    // bgg prepares collection in background, queued retry waits for it
+    let body = scraper.get(&path)?;
+    let doc = Document::from(body.as_str());
+    filter_collection(doc, &path)
+}
+
+fn filter_collection(doc: Document, path: &str) -> Result<UserStats, Error> {
+    // unknown users get status 200 with an error message instead of items
+    if let Some(message) = doc.find(Name("errors")).flat_map(|e| e.find(Name("message"))).next() {
+        let message = message.text();
+        if message.contains("Invalid username") {
+            return Err(Error::http(path, StatusCode::NOT_FOUND));
+        }
+        broken!("Bgg has no collection: {}", message.trim())
+    }
+    let ratings = doc
+        .find(Name("stats"))
+        .flat_map(|s| s.find(Name("rating")).take(1));
+
+    let mut n: u32 = 0;
+    let mut sum = 0.0;
+    let mut squares = 0.0;
+    for rating in ratings {
+        match rating.attr("value") {
+            Some("N/A") => {}, // not rated
+            Some(v) => {
+                let v = v.parse::<f64>()?;
+                sum += v;
+                squares += v * v;
+                n += 1;
+            },
+            None => broken!("Can't parse rating in the collection")
+        }
+    }
+    // user has rated nothing, policy won't trust him
+    if n == 0 {
+        return Ok(UserStats::empty());
+    }
+    let average = sum / f64::from(n);
+    // rounding can push variance of equal ratings a bit below zero
+    let std_dev = (squares / f64::from(n) - average * average).max(0.0).sqrt();
+    Ok(UserStats { average, ratings: Some(n), std_dev: Some(std_dev) })
 }
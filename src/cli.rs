@@ -1,25 +1,167 @@
 use structopt::StructOpt;
+use std::path::PathBuf;
+use crate::report::{Format, Score, Sort};
 
 #[derive(Debug, StructOpt)]
 /// Utility to reevaluate bgg top
 /// ignoring overhyped users.
-pub enum Cli {
+pub struct Cli {
+    #[structopt(long = "workspace", env = "BGG_SWING_WORKSPACE", parse(from_os_str), raw(global = "true"))]
+    /// Directory with top.db and app.config,
+    /// current one by default.
+    pub workspace: Option<PathBuf>,
+    #[structopt(long = "db", env = "BGG_SWING_DB", parse(from_os_str), raw(global = "true"))]
+    /// Path to .db file, overrides workspace.
+    pub db: Option<PathBuf>,
+    #[structopt(long = "config", env = "BGG_SWING_CONFIG", parse(from_os_str), raw(global = "true"))]
+    /// Path to .config file, overrides workspace.
+    pub config: Option<PathBuf>,
+    #[structopt(subcommand)]
+    pub command: Command
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
     #[structopt(name = "new")]
     /// Creates new .db and .config files.
     New { },
     #[structopt(name = "report")]
     /// Prints arranged list of games if it
     /// has been stabilized.
-    Report { },
+    Report {
+        #[structopt(long = "format", default_value = "tsv")]
+        /// tsv, csv, json, markdown or html.
+        format: Format,
+        #[structopt(long = "score", default_value = "rating")]
+        /// rating, bayes, weighted or normalized,
+        /// score that gives filtered rank.
+        score: Score,
+        #[structopt(long = "sort", default_value = "rank")]
+        /// rank or shift, i.e. difference between
+        /// bgg rank and filtered rank.
+        sort: Sort,
+        #[structopt(long = "output", parse(from_os_str))]
+        /// Saves report to file instead of printing it.
+        output: Option<PathBuf>,
+        #[structopt(long = "partial")]
+        /// Reports balanced games so far and lists
+        /// the rest as pending with running scores.
+        /// Bgg rank counts balanced games first.
+        partial: bool
+    },
     #[structopt(name = "pull")]
     /// Pulls games from bgg with n user ratings.
     /// Ignores extensions. Takes n from config file.
-    Pull { },
+    /// Known games keep their balancing state.
+    Pull {
+        #[structopt(long = "resume")]
+        /// Continues unfinished pull from the last
+        /// saved page, starts over if there is none.
+        resume: bool,
+        #[structopt(flatten)]
+        net: Net
+    },
     #[structopt(name = "balance")]
     /// Runs balancing processes until game list is 
-    /// stabilized.
-    Balance { },
+    /// stabilized. Judges saved users with current
+    /// config first.
+    Balance {
+        #[structopt(flatten)]
+        net: Net
+    },
+    #[structopt(name = "recompute")]
+    /// Judges users and rates balanced games again
+    /// with current config. Uses only saved data,
+    /// makes no requests to bgg.
+    Recompute { },
+    #[structopt(name = "status")]
+    /// Prints balancing progress and estimated
+    /// time to finish it.
+    Status { },
+    #[structopt(name = "migrate")]
+    /// Upgrades .db file to the latest schema.
+    /// Every other command does it on its own.
+    Migrate {
+        #[structopt(long = "dry-run")]
+        /// Only lists migrations that would be applied.
+        dry_run: bool
+    },
+    #[structopt(name = "snapshot")]
+    /// Saves current report along with config
+    /// to compare it later with diff.
+    Snapshot {
+        #[structopt(long = "list", conflicts_with = "label")]
+        /// Lists saved snapshots instead.
+        list: bool,
+        /// Name to refer to snapshot by,
+        /// its id works too.
+        label: Option<String>
+    },
+    #[structopt(name = "diff")]
+    /// Shows how filtered top has changed between
+    /// two snapshots given by label or id.
+    Diff {
+        /// Older snapshot.
+        a: String,
+        /// Newer snapshot.
+        b: String,
+        #[structopt(long = "score", default_value = "rating")]
+        /// rating, bayes, weighted or normalized,
+        /// score that gives filtered rank.
+        score: Score
+    },
+    #[structopt(name = "config")]
+    /// Shows, changes or checks config file.
+    /// New files are toml and can have comments,
+    /// files starting with { are read as json.
+    /// Set keeps the format, not the comments.
+    Config {
+        #[structopt(subcommand)]
+        command: ConfigCommand
+    },
     #[structopt(name = "review")]
-    /// Marks users as unstable again after a period.
+    /// Marks users as unstable again after a period
+    /// taken from config file. Games rated by them
+    /// are scored again from saved ratings once
+    /// users are judged, games without saved
+    /// ratings are balanced again.
     Review { }
 }
+
+#[derive(Debug, StructOpt)]
+pub enum ConfigCommand {
+    #[structopt(name = "show")]
+    /// Prints config with defaults for missing keys.
+    Show { },
+    #[structopt(name = "set")]
+    /// Changes a value and rewrites the file,
+    /// comments are not kept.
+    Set {
+        /// Dotted path, e.g. retry.queued.tries.
+        key: String,
+        /// Json value, plain text for strings.
+        value: String
+    },
+    #[structopt(name = "validate")]
+    /// Checks values, lists missing keys and
+    /// unknown ones, e.g. typos or renamed keys.
+    Validate { }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Net {
+    #[structopt(long = "base-url")]
+    /// Overrides bgg base url from config file.
+    pub base_url: Option<String>,
+    #[structopt(long = "record", parse(from_os_str))]
+    /// Saves every bgg response to cassette directory.
+    pub record: Option<PathBuf>,
+    #[structopt(long = "replay", parse(from_os_str), conflicts_with = "record")]
+    /// Serves bgg responses from cassette directory
+    /// without network access.
+    pub replay: Option<PathBuf>,
+    #[structopt(long = "fake", parse(from_os_str), raw(conflicts_with_all = r#"&["record", "replay"]"#))]
+    /// Answers bgg requests with scripted data from
+    /// json file instead of network.
+    pub fake: Option<PathBuf>
+}
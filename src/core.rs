@@ -1,75 +1,210 @@
 use crate::db;
 use crate::bgg;
-use crate::lib::{Game, User};
-use failure::{Error, ResultExt, ensure};
+use crate::lib::{Game, User, Pending, Progress, PullDiff, Snapshot};
+use crate::error::Error;
+use crate::trust::TrustPolicy;
 use std::fs;
-use serde_json::{from_str, to_string_pretty};
+use serde_json::{from_str, from_value, to_string, to_string_pretty, to_value, Value};
 use serde_derive::{Serialize, Deserialize};
 use std::thread;
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver, TryRecvError};
 use std::time::Duration;
-use reqwest::Client;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::path::PathBuf;
 
 const CONFIG_FILE_NAME: &str = "app.config";
-const LOWER_BOUND: f64 = 2.0;
-const UPPER_BOUND: f64 = 8.0;
 const MISS_CHANCE: f32 = 0.5;
+// the least idle threads add to their sleep every round, so they never busy poll db
+const IDLE_STEP: Duration = Duration::from_millis(10);
 
-pub fn create_structure() -> Result<(), Error> {
+pub fn create_structure(ws: &Workspace) -> Result<(), Error> {
+    // workspace directory could be new
+    for path in &[&ws.config, &ws.db] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| Error::io(dir.display(), e))?;
+        }
+    }
     // create config file
-    let new_conf = to_string_pretty(&Config::new(1000, 20, 500, 1000, 50000))?;
-    fs::write(CONFIG_FILE_NAME, new_conf)?;
+    write_config(ws, &Config::default())?;
     // create db file
-    db::initialize()?;
+    db::initialize(&ws.db)?;
     Ok(())
 }
 
-pub fn pull_games(limit: u32, progress: impl Fn(usize) -> ()) -> Result<(), Error> {
-    ensure!(limit > 0, "Can't get top.");
+pub fn pull_games(ws: &Workspace, config: &Config, source: &dyn bgg::BggSource, resume: bool, progress: impl Fn(u32)) -> Result<PullDiff, Error> {
+    if config.limit == 0 {
+        return Err(Error::Config(String::from("Can't get top, limit must be above zero.")));
+    }
 
-    // clear db
-    db::drop_all_games()?;
-    // Collect games
-    for (i, games) in bgg::GameIterator::new(&Client::new(), limit).enumerate() {
+    // find where to start, without unfinished pull resume starts over
+    let state = if resume { db::get_pull_state(&ws.db)? } else { None };
+    let (games, done) = match state {
+        Some((limit, _)) if limit != config.limit =>
+            return Err(Error::Config(format!("Unfinished pull used limit {}, set it back to resume.", limit))),
+        Some((_, page)) => (bgg::GameIterator::resume(source, config.limit, page, db::get_staged_first(&ws.db, page)?), page),
+        None => {
+            db::start_pull(&ws.db, config.limit)?;
+            (bgg::GameIterator::new(source, config.limit), 0)
+        }
+    };
+    // Collect games, every page is saved to staging
+    for (i, games) in games.enumerate() {
         // Error will be elevated and next() will be never called again
-        let games_on_page = games?;
-        db::add_games(games_on_page)?;
-        progress(i + 1);
+        let page = done + i as u32 + 1;
+        db::stage_page(&ws.db, page, &games?)?;
+        progress(page);
     }
-    Ok(())
+    // games are changed only when the whole list is known
+    db::finish_pull(&ws.db)
+}
+
+pub fn review_users(ws: &Workspace, config: &Config) -> Result<(u32, u32), Error> {
+    let mut conn = db::DbConn::new(&ws.db)?;
+    conn.review_users(config.trusted_for, config.untrusted_for)
 }
 
-pub fn make_report() -> Result<Vec<Game>, Error> {
-    let conn = db::DbConn::new()?;
+pub fn make_report(ws: &Workspace) -> Result<Vec<Game>, Error> {
+    let conn = db::DbConn::new(&ws.db)?;
     if conn.get_number_of_unstable_games()? == 0 {
-        db::get_all_games()
+        db::get_all_games(&ws.db)
     } else {
         Ok(Vec::new())
     }
 }
 
+/// Freezes the report with config it was made by, None while
+/// game list is not stable.
+pub fn take_snapshot(ws: &Workspace, config: &Config, label: Option<String>) -> Result<Option<Snapshot>, Error> {
+    if let Some(label) = &label {
+        // numbers refer to snapshot ids
+        if label.parse::<u32>().is_ok() {
+            return Err(Error::Snapshot(format!("label {} is a number, use some text", label)));
+        }
+    }
+    let games = make_report(ws)?;
+    if games.is_empty() {
+        return Ok(None);
+    }
+    let conf = to_string(config)
+        .map_err(|e| Error::Config(e.to_string()))?;
+    Ok(Some(db::save_snapshot(&ws.db, label.as_deref(), &conf, &games)?))
+}
+
+pub fn snapshots(ws: &Workspace) -> Result<Vec<Snapshot>, Error> {
+    db::get_snapshots(&ws.db)
+}
+
+/// Snapshot by label or id along with its games.
+pub fn load_snapshot(ws: &Workspace, key: &str) -> Result<(Snapshot, Vec<Game>), Error> {
+    let snapshot = db::find_snapshot(&ws.db, key)?
+        .ok_or_else(|| Error::Snapshot(format!("there is no snapshot {}", key)))?;
+    let games = db::get_snapshot_games(&ws.db, &snapshot)?;
+    Ok((snapshot, games))
+}
+
+/// Judges users again and rescores balanced games with current config,
+/// using only what is saved in db. Games balanced before ratings were
+/// saved get bayes score from their old rating, their count is returned last.
+pub fn recompute(ws: &Workspace, config: &Config) -> Result<(u32, u32, u32), Error> {
+    let mut conn = db::DbConn::new(&ws.db)?;
+    // sorted once, every user is compared with the same population
+    let averages = conn.get_averages()?;
+    let percentile = |average: f64| -> Result<Option<f64>, Error> {
+        if averages.is_empty() {
+            return Ok(None);
+        }
+        let below = averages.partition_point(|&a| a < average);
+        Ok(Some(below as f64 * 100.0 / averages.len() as f64))
+    };
+    let mut verdicts = Vec::new();
+    for (user, stats) in conn.get_judged_users()? {
+        verdicts.push((user, config.trust.trusts(&stats, percentile)?));
+    }
+    let users = conn.set_trust(&verdicts, &config.trust.name)?;
+
+    let population = Population::of(&conn)?;
+    let mut games = conn.get_stable_games()?;
+    for game in games.iter_mut() {
+        score(&conn, game, &population, config)?;
+    }
+    conn.save_scores(&games, &config.trust.name)?;
+
+    let mut legacy = conn.get_legacy_games()?;
+    for game in legacy.iter_mut() {
+        game.bayes = Avg::new(game.votes, game.rating).bayes(config.dummy_votes, config.prior_mean);
+    }
+    conn.save_bayes(&legacy)?;
+    Ok((users, games.len() as u32, legacy.len() as u32))
+}
+
+/// Balanced games and games in progress, the latter are scored
+/// with ratings saved so far.
+pub fn make_partial_report(ws: &Workspace, config: &Config) -> Result<(Vec<Game>, Vec<Pending>), Error> {
+    let conn = db::DbConn::new(&ws.db)?;
+    let balanced = conn.get_balanced_games()?;
+    let population = Population::of(&conn)?;
+    let mut pending = conn.get_pending_games()?;
+    for (game, _) in pending.iter_mut() {
+        score(&conn, game, &population, config)?;
+    }
+    Ok((balanced, pending))
+}
+
+/// Time left for balance to finish.
+pub enum Eta {
+    Done,
+    Measured(Duration), // from the pace of games balanced recently
+    Estimated(Duration) // from config delays, when there is no recent pace
+}
+
+pub fn status(ws: &Workspace, config: &Config) -> Result<(Progress, Eta), Error> {
+    let conn = db::DbConn::new(&ws.db)?;
+    let progress = conn.get_progress()?;
+    let unstable_games = progress.games - progress.stable_games;
+    if unstable_games == 0 {
+        return Ok((progress, Eta::Done));
+    }
+    let (recent, span) = conn.get_recent_pace()?;
+    let eta = if recent > 1 && span > 0.0 {
+        let per_game = span / f64::from(recent - 1);
+        Eta::Measured(Duration::from_secs((per_game * f64::from(unstable_games)) as u64))
+    } else {
+        // threads work in parallel, the slower one decides
+        let games = u64::from(config.g_delay) * u64::from(progress.pages_left + unstable_games);
+        let users = u64::from(config.u_delay) * u64::from(progress.users - progress.stable_users);
+        Eta::Estimated(Duration::from_millis(games.max(users)))
+    };
+    Ok((progress, eta))
+}
+
 fn users_prevail(number_of_games: u32, number_of_users: u32) -> bool {
     (number_of_games as f32 * MISS_CHANCE).floor() as u32 * bgg::USER_PAGE_SIZE < number_of_users
 }
 
-fn trust(rating: f64) -> bool {
-    LOWER_BOUND < rating && rating < UPPER_BOUND
+// Gaussian falloff, user at population mean counts fully
+fn weight(average: f64, mean: f64, width: f64) -> f64 {
+    let z = (average - mean) / width;
+    (-z * z / 2.0).exp()
 }
 
-fn with_cont(tx: Sender<Message>, rx: Receiver<Order>, mut tkn: RegulationToken,
-            continuation: impl Fn(&Sender<Message>, &mut db::DbConn, &Client, &mut RegulationToken) -> ()) -> () {
+// user's rating in population terms, kept on 1..10 scale
+fn normalize(rating: f64, average: f64, std_dev: f64, (mean, spread): (f64, f64)) -> f64 {
+    let z = (rating - average) / std_dev;
+    (mean + z * spread).clamp(1.0, 10.0)
+}
+
+fn with_cont(tx: Sender<Message>, rx: Receiver<Order>, mut tkn: RegulationToken, source: bgg::Source, config: Config, db: PathBuf,
+            continuation: impl Fn(&Sender<Message>, &mut db::DbConn, &dyn bgg::BggSource, &mut RegulationToken, &Config)) {
     // Configure thread
-    let mut conn = match db::DbConn::new() {
+    let mut conn = match db::DbConn::new(&db) {
             Err(e) => {
                 tx.send(Message::Err(e)).unwrap();
                 return;
             },
             Ok(cn) => cn
     };
-    let client = Client::new();
     // Start doing main job
     loop {
         // check if we got stop command
@@ -82,16 +217,16 @@ fn with_cont(tx: Sender<Message>, rx: Receiver<Order>, mut tkn: RegulationToken,
         }
         // check if token stop flag is raised
         if tkn.is_stopped() {
-            let e = failure::err_msg("Regulation token stopped the process");
-            tx.send(Message::Err(e)).unwrap();
+            tx.send(Message::Err(Error::Exhausted)).unwrap();
             break;
         }
         thread::sleep(tkn.delay());
-        continuation(&tx, &mut conn, &client, &mut tkn);
+        continuation(&tx, &mut conn, source.as_ref(), &mut tkn, &config);
     }
 }
 
-fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client, tkn: &mut RegulationToken) -> () {
+fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, source: &dyn bgg::BggSource, tkn: &mut RegulationToken,
+                   config: &Config) {
     // check if we potentially has a work to do
     let number_of_games = match conn.get_number_of_unstable_games() {
         Err(e) => {
@@ -120,7 +255,7 @@ fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
         Ok(gb) => gb
     };
     // if game is None, there is no more unstable games
-    let (mut game, temp) = match gamebox {
+    let (mut game, first_page, rescore) = match gamebox {
         None => {
             tx.send(Message::Stabilized).unwrap();
             return;
@@ -128,22 +263,34 @@ fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
         Some(g) => g
     };
     tx.send(Message::Info(game.clone())).unwrap();
-    // ask for user ratings
-    let mut avg = Avg::new(temp.n, temp.r);
-    for (i, page) in bgg::UserIterator::new(&client, game.id, temp.page).enumerate() {
+    // fresh pass, ratings removed from bgg should not linger
+    if first_page == 1 && !rescore {
+        if let Err(e) = conn.drop_ratings(&game) {
+            tx.send(Message::Err(e)).unwrap();
+            return;
+        }
+    }
+    // ask for user ratings, reviewed game has them all saved
+    let pages = if rescore { None } else { Some(bgg::UserIterator::new(source, game.id, first_page)) };
+    for (i, page) in pages.into_iter().flatten().enumerate() {
         // save new page to db
-        let new_page = temp.page + i as u32;
-        match conn.update_page(&game, new_page, avg.n(), avg.result()) {
+        let new_page = first_page + i as u32;
+        match conn.update_page(&game, new_page) {
             Err(e) => {
                 tx.send(Message::Err(e)).unwrap();
                 return;
             },
             Ok(_) => {}
         };
-        let users = match page {
+        let ratings = match page {
+            Err(ref e) if e.is_gone() => break, // game was removed, keep what we have
             Err(e) => {
-                tx.send(Message::Notification(e)).unwrap();
-                tkn.harden(); // wait a bit longer before next request
+                if e.is_transient() {
+                    tx.send(Message::Notification(e)).unwrap();
+                    tkn.harden(); // wait a bit longer before next request
+                } else {
+                    tx.send(Message::Err(e)).unwrap(); // asking again won't help
+                }
                 return;
             },
             Ok(vec) => {
@@ -152,7 +299,7 @@ fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
             }
         };
         // batch insert them to db
-        let usernames: Vec<&User> = users.iter().map(|(u, _)| u).collect();
+        let usernames: Vec<&User> = ratings.iter().map(|r| &r.user).collect();
         match conn.add_users(&usernames) {
             Err(e) => {
                 tx.send(Message::Err(e)).unwrap();
@@ -160,26 +307,41 @@ fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
             },
             Ok(_) => {}
         };
-        // check user stability and trust
-        for (user, rating) in users {
-            match conn.check_user(&user) {
+        if let Err(e) = conn.add_ratings(&game, &ratings) {
+            tx.send(Message::Err(e)).unwrap();
+            return;
+        }
+        // check user stability, trust is applied to saved ratings later
+        for rating in &ratings {
+            match conn.check_user(&rating.user) {
                 Err(e) => {
                     tx.send(Message::Err(e)).unwrap();
                     return;
                 },
                 Ok(None) => return, // user is unstable, move along
-                Ok(Some(true)) => avg.add(rating),
-                Ok(Some(false)) => {} // can't trust, ignore
+                Ok(Some(_)) => {}
             }
         }
         // Prevent 429 Too many requests
         thread::sleep(tkn.delay());
     }
+    // reviewed users are judged by user threads
+    match conn.has_unstable_raters(&game) {
+        Err(e) => {
+            tx.send(Message::Err(e)).unwrap();
+            return;
+        },
+        Ok(true) => return,
+        Ok(false) => {}
+    }
     // every user was stable
-    // save average and number of users
-    game.rating = avg.result();
-    game.votes = avg.n();
-    match conn.update_game(&game) {
+    let scored = Population::of(conn)
+        .and_then(|population| score(conn, &mut game, &population, config));
+    if let Err(e) = scored {
+        tx.send(Message::Err(e)).unwrap();
+        return;
+    }
+    match conn.update_game(&game, &config.trust.name) {
         Err(e) => {
             tx.send(Message::Err(e)).unwrap();
             return;
@@ -188,33 +350,96 @@ fn stabilize_games(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
     };
 }
 
-fn stabilize_users(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client, tkn: &mut RegulationToken) -> () {
-    let user = match conn.get_unstable_user() {
+// What weighting and normalization compare users with.
+struct Population {
+    mean: Option<f64>,
+    scale: Option<(f64, f64)>
+}
+
+impl Population {
+    fn of(conn: &db::DbConn) -> Result<Population, Error> {
+        Ok(Population { mean: conn.population_mean()?, scale: conn.population_scale()? })
+    }
+}
+
+// Every score of a game is computed from saved ratings only.
+fn score(conn: &db::DbConn, game: &mut Game, population: &Population, config: &Config) -> Result<(), Error> {
+    let ratings = conn.get_ratings(game)?;
+    let Population { mean, scale } = *population;
+    let mut avg = Avg::new(0, 0.0);
+    let mut weighted = WeightedAvg::new(0.0, 0.0);
+    let mut normalized = Avg::new(0, 0.0);
+    for (rating, verdict) in ratings {
+        if verdict.trusted {
+            avg.add(rating);
+        }
+        // users unknown to bgg weigh nothing
+        if let (Some(average), Some(mean)) = (verdict.average, mean) {
+            weighted.add(rating, weight(average, mean, config.weight_width));
+        }
+        // spread is needed to normalize, users who rate everything the same are skipped
+        if let (Some(average), Some(std_dev), Some(scale)) = (verdict.average, verdict.std_dev, scale) {
+            if std_dev > 0.0 {
+                normalized.add(normalize(rating, average, std_dev, scale));
+            }
+        }
+    }
+    game.rating = avg.result();
+    game.bayes = avg.bayes(config.dummy_votes, config.prior_mean);
+    game.votes = avg.n();
+    game.weighted_rating = weighted.result();
+    game.weighted_votes = weighted.weight();
+    game.normalized_rating = normalized.result();
+    game.normalized_votes = normalized.n();
+    Ok(())
+}
+
+fn stabilize_users(tx: &Sender<Message>, conn: &mut db::DbConn, source: &dyn bgg::BggSource, tkn: &mut RegulationToken,
+                   config: &Config, worker: u32) {
+    let user = match conn.claim_user(worker) {
         Err(e) => {
             tx.send(Message::Err(e)).unwrap();
             return;
         },
         Ok(u) => u
     };
-    // if user is None but Order::Stop was not recieved, just wait
+    // if user is None but Order::Stop was not recieved, wait longer every time,
+    // games thread adds more users or stops every thread once it is done,
+    // so having nothing to do is not a failure and does not use up attempts
     let user = match user {
         None => {
-            tkn.harden(); // to prevent eternal loop
+            tkn.rest();
             return;
         },
         Some(u) => u
     };
     // ask bgg for user stats
-    let rating = match bgg::get_user_average_rating(client, &user) {
+    let stats = match source.user_stats(&user) {
+        // user was removed, can't trust him
+        Err(ref e) if e.is_gone() => None,
         Err(e) => {
-            tx.send(Message::Notification(e)).unwrap();
-            tkn.harden(); // wait a bit longer before next request
+            if e.is_transient() {
+                tx.send(Message::Notification(e)).unwrap();
+                tkn.harden(); // wait a bit longer before next request
+            } else {
+                tx.send(Message::Err(e)).unwrap(); // asking again won't help
+            }
             return;
         },
-        Ok(rate) => rate
+        Ok(stats) => Some(stats)
+    };
+    let trusted = match stats {
+        None => false,
+        Some(ref s) => match config.trust.trusts(s, |a| conn.average_percentile(a)) {
+            Err(e) => {
+                tx.send(Message::Err(e)).unwrap();
+                return;
+            },
+            Ok(t) => t
+        }
     };
     // save user to db
-    match conn.update_user(&user, trust(rating)){
+    match conn.update_user(&user, trusted, stats, &config.trust.name) {
         Err(e) => {
             tx.send(Message::Err(e)).unwrap();
             return;
@@ -226,28 +451,52 @@ fn stabilize_users(tx: &Sender<Message>, conn: &mut db::DbConn, client: &Client,
     }
 }
 
-pub fn stabilize(config: Config, running: Arc<AtomicBool>, mut progress: impl FnMut(Message) -> ()) -> Result<(), Error> {
+pub fn stabilize(ws: &Workspace, config: Config, source: bgg::Source, running: Arc<AtomicBool>, mut progress: impl FnMut(Message) -> ()) -> Result<(), Error> {
     // NB. Errors from mpsc channels use unwrap(). If channels fail,
     // the core of the programm is severely damaged, panic is only option. 
     
     
+    // users claimed by an interrupted run are free again
+    db::DbConn::new(&ws.db)?.release_users()?;
+
     // First comm network
     let (games_tx, main_rx) = mpsc::channel();
     let users_tx = mpsc::Sender::clone(&games_tx);
-    // Second and third comm networks
+    // Second comm network, one channel for every thread
     let (main_tx1, games_rx) = mpsc::channel();
-    let (main_tx2, users_rx) = mpsc::channel();
+    let mut users_txs = Vec::new();
 
     // try to balance every game
     // that must be the only source of Message::Stabilized
     let delay_step = Duration::from_millis(config.g_delay as u64);
     let prevail_for = Duration::from_millis(config.prevail_for as u64);
     let g_tkn = RegulationToken::new(config.attempts, delay_step, prevail_for);
-    thread::spawn(move || with_cont(games_tx, games_rx, g_tkn, stabilize_games ));
-    // try to balance every user
+    let g_source = Arc::clone(&source);
+    let g_config = config.clone();
+    let g_db = ws.db.clone();
+    thread::spawn(move || with_cont(games_tx, games_rx, g_tkn, g_source, g_config, g_db, stabilize_games ));
+    // try to balance every user, workers claim distinct users
+    // and share the rate limit of the source
     let delay_step = Duration::from_millis(config.u_delay as u64);
-    let u_tkn = RegulationToken::new(config.attempts, delay_step, prevail_for);
-    thread::spawn(move || with_cont(users_tx, users_rx, u_tkn, stabilize_users ));
+    for worker in 0..config.user_workers {
+        let (main_tx, users_rx) = mpsc::channel();
+        users_txs.push(main_tx);
+        let worker_tx = mpsc::Sender::clone(&users_tx);
+        let u_tkn = RegulationToken::new(config.attempts, delay_step, prevail_for);
+        let u_source = Arc::clone(&source);
+        let u_config = config.clone();
+        let u_db = ws.db.clone();
+        thread::spawn(move || with_cont(worker_tx, users_rx, u_tkn, u_source, u_config, u_db,
+            move |tx, conn, source, tkn, config| stabilize_users(tx, conn, source, tkn, config, worker)));
+    }
+    // every thread holds its own sender, main loop ends when all of them are gone
+    drop(users_tx);
+    let stop = || {
+        main_tx1.send(Order::Stop).unwrap_or_default();
+        for main_tx in &users_txs {
+            main_tx.send(Order::Stop).unwrap_or_default();
+        }
+    };
 
     // This will block main until iterator yields None
     let mut result: Result<(), Error> = Ok(());
@@ -255,44 +504,218 @@ pub fn stabilize(config: Config, running: Arc<AtomicBool>, mut progress: impl Fn
         // handle messages
         match received {
             Message::Err(e) => {
-                main_tx1.send(Order::Stop).unwrap_or_default();
-                main_tx2.send(Order::Stop).unwrap_or_default();                
+                stop();
                 result = Err(e);
             },
-            Message::Stabilized => {
-                main_tx1.send(Order::Stop).unwrap_or_default();
-                main_tx2.send(Order::Stop).unwrap_or_default();
-            },
+            Message::Stabilized => stop(),
             msg => progress(msg)
         }
         // handle stop signal
         if !running.load(Ordering::SeqCst) {
-            main_tx1.send(Order::Stop).unwrap_or_default();
-            main_tx2.send(Order::Stop).unwrap_or_default();
+            stop();
         }
     }
     result
 }
 
-pub fn config() -> Result<Config, Error> {
-    let conf = fs::read_to_string(CONFIG_FILE_NAME)
-        .with_context(|_| format!("Can't open: {}", CONFIG_FILE_NAME))?;
-    let conf = from_str(&conf)?;
-    Ok(conf)
+/// Config with defaults for missing keys, fails if any value is out of range.
+pub fn config(ws: &Workspace) -> Result<Config, Error> {
+    let config = load_config(ws)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Config as it would be used, in the format of the file.
+pub fn show_config(ws: &Workspace) -> Result<String, Error> {
+    render_config(is_toml(&config_text(ws)?), &load_config(ws)?)
+}
+
+/// Changes a value by dotted key, e.g. retry.queued.tries, and rewrites
+/// the file. Value is taken as json, as a plain string if it is not one.
+pub fn set_config(ws: &Workspace, key: &str, value: &str) -> Result<Config, Error> {
+    let mut conf = to_value(load_config(ws)?)
+        .map_err(|e| Error::Config(e.to_string()))?;
+    let target = key.split('.')
+        .try_fold(&mut conf, |v, k| match k.parse::<usize>() {
+            Ok(i) if v.is_array() => v.get_mut(i),
+            _ => v.get_mut(k)
+        })
+        .ok_or_else(|| Error::Config(format!("unknown key {}", key)))?;
+    *target = from_str(value).unwrap_or_else(|_| Value::String(value.to_string()));
+    let config: Config = from_value(conf)
+        .map_err(|e| Error::Config(format!("{}: {}", key, e)))?;
+    config.validate()?;
+    write_config(ws, &config)?;
+    Ok(config)
+}
+
+/// Validates config, returns keys missing from the file and keys
+/// config does not know, e.g. typos or renamed ones, both are ignored.
+pub fn check_config(ws: &Workspace) -> Result<(Vec<String>, Vec<String>), Error> {
+    let file = read_config(ws)?;
+    let config = load_config(ws)?;
+    config.validate()?;
+    let full = to_value(&config)
+        .map_err(|e| Error::Config(e.to_string()))?;
+    let mut missing = Vec::new();
+    missing_keys(&file, &full, "", &mut missing);
+    // unknown keys are the ones missing from full config
+    let mut unknown = Vec::new();
+    missing_keys(&full, &file, "", &mut unknown);
+    Ok((missing, unknown))
+}
+
+fn missing_keys(file: &Value, full: &Value, prefix: &str, missing: &mut Vec<String>) {
+    if let (Value::Object(file), Value::Object(full)) = (file, full) {
+        for (key, value) in full {
+            let path = format!("{}{}", prefix, key);
+            match file.get(key) {
+                None => missing.push(path),
+                Some(v) => missing_keys(v, value, &format!("{}.", path), missing)
+            }
+        }
+    }
+}
+
+// json files are a single object, anything else is toml, it allows comments
+fn is_toml(text: &str) -> bool {
+    !text.trim_start().starts_with('{')
+}
+
+fn config_text(ws: &Workspace) -> Result<String, Error> {
+    fs::read_to_string(&ws.config)
+        .map_err(|e| Error::io(ws.config.display(), e))
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+fn read_config(ws: &Workspace) -> Result<Value, Error> {
+    let text = config_text(ws)?;
+    let conf = if is_toml(&text) {
+        toml::from_str(&text).map_err(|e| e.to_string())
+    } else {
+        from_str(&text).map_err(|e| e.to_string())
+    };
+    conf.map_err(|e| Error::Config(format!("{}: {}", ws.config.display(), e)))
+}
+
+// unvalidated, so that set could fix a wrong value
+fn load_config(ws: &Workspace) -> Result<Config, Error> {
+    from_value(read_config(ws)?)
+        .map_err(|e| Error::Config(format!("{}: {}", ws.config.display(), e)))
+}
+
+fn render_config(toml: bool, config: &Config) -> Result<String, Error> {
+    let text = if toml {
+        // toml wants plain values before tables, its own Value sorts them
+        toml::Value::try_from(config).and_then(|v| toml::to_string_pretty(&v)).map_err(|e| e.to_string())
+    } else {
+        to_string_pretty(config).map(|mut t| { t.push('\n'); t }).map_err(|e| e.to_string())
+    };
+    text.map_err(Error::Config)
+}
+
+// file keeps its format, new ones are toml
+fn write_config(ws: &Workspace, config: &Config) -> Result<(), Error> {
+    let toml = config_text(ws).map(|t| is_toml(&t)).unwrap_or(true);
+    let text = render_config(toml, config)?;
+    fs::write(&ws.config, text)
+        .map_err(|e| Error::io(ws.config.display(), e))
+}
+
+/// Where db and config files are, several workspaces keep
+/// rankings side by side.
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub db: PathBuf,
+    pub config: PathBuf
+}
+
+impl Workspace {
+    /// Files have default names inside dir, current directory if None,
+    /// unless given explicitly.
+    pub fn new(dir: Option<PathBuf>, db: Option<PathBuf>, config: Option<PathBuf>) -> Workspace {
+        let dir = dir.unwrap_or_default();
+        Workspace {
+            db: db.unwrap_or_else(|| dir.join(db::DB_FILE_NAME)),
+            config: config.unwrap_or_else(|| dir.join(CONFIG_FILE_NAME))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)] // keys added later are filled in for older files
 pub struct Config {
     pub limit: u32, // number or user ratings for a game
     pub attempts: u32, // number or errors that thread can handle before stop
     pub u_delay: u32, // ms, delay increase after every failure for stabilize_users
     pub g_delay: u32, // ms, delay increase after every failure for stabilize_games
-    pub prevail_for: u32 // ms, sleep time for game thread when users pvevail
+    pub prevail_for: u32, // ms, sleep time for game thread when users pvevail
+    pub trusted_for: u32, // days, trusted user is reviewed after that period
+    pub untrusted_for: u32, // days, untrusted user is reviewed after that period
+    pub dummy_votes: u32, // votes of prior_mean added to every game for bayes score
+    pub prior_mean: f64, // rating of dummy votes
+    pub trust: TrustPolicy, // which users are not overhyped
+    pub weight_width: f64, // rating points, how fast weight falls off away from population mean
+    pub base_url: String, // every bgg endpoint is built on it
+    pub user_method: bgg::UserMethod, // where to look for user average
+    pub retry: bgg::RetryPolicy, // how to handle 202, 429 and 5xx from bgg
+    pub user_workers: u32, // threads asking bgg about users at once
+    pub rate_limit: u32 // requests per minute to bgg from all threads together, 0 for no limit with one user worker
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            limit: 1000,
+            attempts: 20,
+            u_delay: 500,
+            g_delay: 1000,
+            prevail_for: 50000,
+            trusted_for: 180,
+            untrusted_for: 90,
+            dummy_votes: 1500, // bgg does not publish its count, that is the community estimate
+            prior_mean: 5.5,
+            trust: TrustPolicy::default(),
+            weight_width: 1.5,
+            base_url: String::from(bgg::BASE_URL),
+            user_method: bgg::UserMethod::Collection,
+            retry: bgg::RetryPolicy::default(),
+            user_workers: 1,
+            rate_limit: 0 // delays alone pace the threads
+        }
+    }
 }
 
 impl Config {
-    fn new(limit: u32, attempts: u32, u_delay: u32, g_delay: u32, prevail_for: u32) -> Config {
-        Config {limit, attempts, u_delay, g_delay, prevail_for}
+    /// Every out of range value at once.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut problems = Vec::new();
+        if self.limit == 0 {
+            problems.push(String::from("limit must be above zero"));
+        }
+        if self.attempts == 0 {
+            problems.push(String::from("attempts must be above zero"));
+        }
+        if self.user_workers == 0 {
+            problems.push(String::from("user_workers must be above zero"));
+        }
+        if self.user_workers > 1 && self.rate_limit == 0 {
+            problems.push(format!("rate_limit must be above zero for {} user_workers", self.user_workers));
+        }
+        if !(1.0..=10.0).contains(&self.prior_mean) {
+            problems.push(format!("prior_mean must be within 1..10, got {}", self.prior_mean));
+        }
+        if self.weight_width <= 0.0 {
+            problems.push(format!("weight_width must be above zero, got {}", self.weight_width));
+        }
+        if !self.base_url.starts_with("http://") && !self.base_url.starts_with("https://") {
+            problems.push(format!("base_url must start with http:// or https://, got {}", self.base_url));
+        }
+        problems.extend(self.trust.problems());
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Config(problems.join("; ")))
+        }
     }
 }
 
@@ -314,27 +737,36 @@ struct RegulationToken {
     limit: u32,
     delay_step: Duration,
     i: u32,
+    idle: u32, // rounds in a row with nothing to do
     pub prevail_for: Duration
 }
 
 impl RegulationToken {
     fn new(limit: u32, delay_step: Duration, prevail_for: Duration) -> RegulationToken {
-        RegulationToken { limit, delay_step, i: 0 , prevail_for}
+        RegulationToken { limit, delay_step, i: 0, idle: 0, prevail_for }
     }
     fn delay(&self) -> Duration {
-        self.delay_step * (self.i + 1)
+        self.delay_step * (self.i + 1) + self.delay_step.max(IDLE_STEP) * self.idle
     }
     fn is_stopped(&self) -> bool {
         self.i >= self.limit
     }
     fn ease(&mut self) -> () {
+        self.idle = 0;
         if !self.is_stopped() && self.i != 0 {
             self.i -= 1;
         }
     }
     fn harden(&mut self) -> () {
+        self.idle = 0;
         self.i += 1;
     }
+    // nothing to do, back off up to the limit without getting closer to stop
+    fn rest(&mut self) -> () {
+        if self.idle < self.limit {
+            self.idle += 1;
+        }
+    }
 }
 
 struct Avg {
@@ -353,7 +785,207 @@ impl Avg {
     fn result(&self) -> f64 {
         self.val
     }
+    // average with `votes` dummy ratings of `mean` added, few votes can't outweigh them
+    fn bayes(&self, votes: u32, mean: f64) -> f64 {
+        if votes + self.n == 0 {
+            return mean;
+        }
+        (votes as f64 * mean + self.n as f64 * self.val) / (votes + self.n) as f64
+    }
     fn n(&self) -> u32 {
         self.n
     }
 }
+
+struct WeightedAvg {
+    w: f64,
+    val: f64
+}
+
+impl WeightedAvg {
+    fn new(w: f64, val: f64) -> WeightedAvg {
+        WeightedAvg { w, val }
+    }
+    fn add(&mut self, nmbr: f64, weight: f64) {
+        if weight <= 0.0 {
+            return;
+        }
+        self.w += weight;
+        self.val += (nmbr - self.val) * weight / self.w;
+    }
+    fn result(&self) -> f64 {
+        self.val
+    }
+    // effective number of votes
+    fn weight(&self) -> f64 {
+        self.w
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeBgg;
+    use serde_json::json;
+    use std::cell::Cell;
+    use std::env;
+    use std::process;
+
+    // fresh workspace with games pulled from the script
+    fn workspace(name: &str, script: &Value) -> Workspace {
+        let dir = env::temp_dir().join(format!("bgg_swing_{}_{}", name, process::id()));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        let ws = Workspace::new(Some(dir), None, None);
+        create_structure(&ws).unwrap();
+        let source = FakeBgg::from_value(script.clone()).unwrap();
+        pull_games(&ws, &config(1), &source, false, |_| {}).unwrap();
+        ws
+    }
+
+    // empty db for threads that only need a connection
+    fn db(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("bgg_swing_{}_{}.db", name, process::id()));
+        fs::remove_file(&path).unwrap_or_default();
+        db::initialize(&path).unwrap();
+        path
+    }
+
+    // no sleeps, only failures count
+    fn config(attempts: u32) -> Config {
+        Config { attempts, u_delay: 0, g_delay: 0, prevail_for: 0, ..Config::default() }
+    }
+
+    fn balance(name: &str, script: Value, attempts: u32) -> (Result<(), Error>, Progress) {
+        let ws = workspace(name, &script);
+        let source: bgg::Source = Arc::new(FakeBgg::from_value(script).unwrap());
+        let running = Arc::new(AtomicBool::new(true));
+        let result = stabilize(&ws, config(attempts), source, running, |_| {});
+        let (progress, _) = status(&ws, &config(attempts)).unwrap();
+        fs::remove_dir_all(ws.db.parent().unwrap()).unwrap_or_default();
+        (result, progress)
+    }
+
+    #[test]
+    fn stops_once_stabilized() {
+        let script = json!({
+            "games": {"1": [{"ok": [{"id": 1, "name": "One"}, {"id": 2, "name": "Two"}]}]},
+            "ratings": {"1": {"1": [{"ok": [["a", 8.0], ["b", 6.0]]}]}, "2": {"1": [{"ok": [["b", 4.0]]}]}},
+            "users": {"a": [{"ok": {"average": 7.0}}], "b": [{"ok": {"average": 5.0}}]}
+        });
+        let (result, progress) = balance("stabilized", script, 1);
+        assert!(result.is_ok());
+        assert_eq!((progress.stable_games, progress.games), (2, 2));
+        assert_eq!((progress.stable_users, progress.users), (2, 2));
+    }
+
+    #[test]
+    fn exhausted_after_attempts_throttled() {
+        let script = json!({
+            "games": {"1": [{"ok": [{"id": 1, "name": "One"}]}]},
+            "ratings": {"1": {"1": [{"ok": [["a", 8.0]]}]}},
+            "users": {"a": ["throttled", "throttled", {"ok": {"average": 7.0}}]}
+        });
+        let (result, progress) = balance("exhausted", script.clone(), 2);
+        match result {
+            Err(Error::Exhausted) => {},
+            r => panic!("expected exhausted, got {:?}", r)
+        }
+        assert_eq!(progress.stable_users, 0);
+        // one more attempt is enough
+        let (result, progress) = balance("not_exhausted", script, 3);
+        assert!(result.is_ok());
+        assert_eq!(progress.stable_users, 1);
+    }
+
+    #[test]
+    fn gone_does_not_use_attempts() {
+        // users missing from script and game 2 are gone
+        let script = json!({
+            "games": {"1": [{"ok": [{"id": 1, "name": "One"}, {"id": 2, "name": "Two"}]}]},
+            "ratings": {"1": {"1": [{"ok": [["a", 8.0], ["b", 6.0], ["c", 5.0]]}]}, "2": {"1": ["gone"]}},
+            "users": {"c": [{"ok": {"average": 5.0}}]}
+        });
+        let (result, progress) = balance("gone", script, 1);
+        assert!(result.is_ok());
+        assert_eq!((progress.stable_games, progress.games), (2, 2));
+        assert_eq!((progress.stable_users, progress.users), (3, 3));
+    }
+
+    #[test]
+    fn token_stops_at_limit() {
+        let step = Duration::from_millis(100);
+        let mut tkn = RegulationToken::new(2, step, Duration::from_millis(0));
+        assert_eq!(tkn.delay(), step);
+        tkn.ease(); // can't go below zero
+        assert_eq!(tkn.delay(), step);
+        tkn.harden();
+        assert_eq!(tkn.delay(), step * 2);
+        assert!(!tkn.is_stopped());
+        tkn.ease();
+        assert_eq!(tkn.delay(), step);
+        tkn.harden();
+        tkn.harden();
+        assert!(tkn.is_stopped());
+        tkn.ease(); // stopped token stays so
+        assert!(tkn.is_stopped());
+    }
+
+    #[test]
+    fn token_rests_without_stopping() {
+        let step = Duration::from_millis(100);
+        let mut tkn = RegulationToken::new(2, step, Duration::from_millis(0));
+        for _ in 0..5 {
+            tkn.rest();
+        }
+        assert!(!tkn.is_stopped());
+        // backoff is capped by the limit and gone with the first work
+        assert_eq!(tkn.delay(), step * 3);
+        tkn.harden();
+        assert_eq!(tkn.delay(), step * 2);
+        tkn.rest();
+        tkn.ease();
+        assert_eq!(tkn.delay(), step);
+        // idle thread sleeps even with no delay configured
+        let mut tkn = RegulationToken::new(2, Duration::from_millis(0), Duration::from_millis(0));
+        tkn.rest();
+        assert_eq!(tkn.delay(), IDLE_STEP);
+    }
+
+    fn run_cont(name: &str, tkn: RegulationToken, stop: bool, harden: bool) -> (u32, Vec<Message>) {
+        let path = db(name);
+        let (tx, rx) = mpsc::channel();
+        let (order_tx, order_rx) = mpsc::channel();
+        if stop {
+            order_tx.send(Order::Stop).unwrap();
+        }
+        let calls = Cell::new(0);
+        let source: bgg::Source = Arc::new(FakeBgg::from_value(json!({})).unwrap());
+        with_cont(tx, order_rx, tkn, source, config(1), path.clone(), |_, _, _, tkn, _| {
+            calls.set(calls.get() + 1);
+            if harden {
+                tkn.harden();
+            }
+        });
+        fs::remove_file(&path).unwrap_or_default();
+        (calls.get(), rx.try_iter().collect())
+    }
+
+    #[test]
+    fn cont_stops_on_order() {
+        let tkn = RegulationToken::new(3, Duration::from_millis(0), Duration::from_millis(0));
+        let (calls, messages) = run_cont("cont_stop", tkn, true, false);
+        assert_eq!(calls, 0);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn cont_reports_exhausted_token() {
+        let tkn = RegulationToken::new(3, Duration::from_millis(0), Duration::from_millis(0));
+        let (calls, messages) = run_cont("cont_exhausted", tkn, false, true);
+        assert_eq!(calls, 3);
+        match &messages[..] {
+            [Message::Err(Error::Exhausted)] => {},
+            m => panic!("expected exhausted, got {:?}", m)
+        }
+    }
+}
@@ -1,75 +1,200 @@
-use rusqlite::{Connection, NO_PARAMS, OpenFlags};
+use rusqlite::{Connection, NO_PARAMS, OpenFlags, Row, Transaction};
 use rusqlite::types::ToSql;
-use failure::{Error, bail};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use chrono::Local;
-use crate::lib::{Game, User, Temp};
-
-const DB_FILE_NAME: &str = "top.db";
-
-pub fn initialize() -> Result<(), Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
-    // create db file
-    conn.execute(
-        "create table if not exists games (
-            id integer primary key,
-            name text not null,
-            rating real,
-            num_votes integer,
-            updated datetime,
-            stable integer,
-            bgg_num_votes integer,
-            bgg_geek_rating real,
-            bgg_avg_rating real,
-            page integer,
-            temp_n integer,
-            temp_r real
-         )",
-        NO_PARAMS,
-    )?;
-    conn.execute(
-        "create table if not exists users (
-            name text primary key,
-            updated datetime,
-            stable integer,
-            trusted integer
-         )",
-        NO_PARAMS,
-    )?;
+use crate::bgg;
+use crate::error::Error;
+use crate::schema::{self, Migration};
+use crate::lib::{Game, User, UserStats, Verdict, Rating, Pending, Progress, PullDiff, Snapshot};
+
+pub const DB_FILE_NAME: &str = "top.db";
+// scores are null until a game is balanced for the first time
+const GAME_COLUMNS: &str = "id, name, coalesce(rating, 0), coalesce(bayes, 0), coalesce(num_votes, 0),
+    coalesce(weighted_rating, 0), coalesce(weighted_votes, 0), coalesce(normalized_rating, 0),
+    coalesce(normalized_votes, 0), bgg_num_votes, bgg_geek_rating, bgg_avg_rating";
+const SNAPSHOT_COLUMNS: &str = "s.id, s.label, s.taken, (select count(*) from snapshot_games where snapshot_id = s.id)";
+
+pub fn initialize(path: &Path) -> Result<(), Error> {
+    // the only place db file is created
+    let mut conn = Connection::open(path)?;
+    schema::migrate(&mut conn)?;
     Ok(())
 }
 
-pub fn drop_all_games() -> Result<(), Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
-    conn.execute("delete from games", NO_PARAMS)?;
+// Every connection sees the latest schema. Missing file is an error,
+// a mistyped path must not end up as a new empty db.
+fn open(path: &Path) -> Result<Connection, Error> {
+    let mut conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    schema::migrate(&mut conn)?;
+    Ok(conn)
+}
+
+/// Version of db and migrations it lacks, db is not changed.
+pub fn pending_migrations(path: &Path) -> Result<(u32, Vec<Migration>), Error> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    Ok((schema::version(&conn)?, schema::pending(&conn)?))
+}
+
+/// Version of db before migration and applied migrations.
+pub fn migrate(path: &Path) -> Result<(u32, Vec<Migration>), Error> {
+    let mut conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let version = schema::version(&conn)?;
+    Ok((version, schema::migrate(&mut conn)?))
+}
+
+/// Forgets any unfinished pull and starts a new one.
+pub fn start_pull(path: &Path, user_limit: u32) -> Result<(), Error> {
+    let mut conn = open(path)?;
+    let tx = conn.transaction()?;
+    tx.execute("delete from staged_games", NO_PARAMS)?;
+    tx.execute("delete from pull_state", NO_PARAMS)?;
+    tx.execute("insert into pull_state (user_limit, page) values (?1, 0)", &[&user_limit as &dyn ToSql])?;
+    tx.commit()?;
     Ok(())
 }
 
-pub fn add_games(games: Vec<Game>) -> Result<(), Error> {
-    let mut conn = Connection::open(DB_FILE_NAME)?;
+/// Limit and the last completed page of unfinished pull.
+pub fn get_pull_state(path: &Path) -> Result<Option<(u32, u32)>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare("select user_limit, page from pull_state")?;
+    match stmt.query_row(NO_PARAMS, |r| (r.get(0), r.get(1))) {
+        Ok(state) => Ok(Some(state)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// The first game of a staged page.
+pub fn get_staged_first(path: &Path, page: u32) -> Result<Option<Game>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare("select id, name, bgg_num_votes, bgg_geek_rating, bgg_avg_rating from staged_games
+        where page = ?1 order by position limit 1")?;
+    match stmt.query_row(&[&page as &dyn ToSql], read_staged) {
+        Ok(game) => Ok(Some(game)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+fn read_staged(r: &Row) -> Game {
+    Game {
+        bgg_num_votes: r.get(2),
+        bgg_geek_rating: r.get(3),
+        bgg_avg_rating: r.get(4),
+        ..Game::new(r.get(0), r.get(1))
+    }
+}
+
+/// Saves a page of pulled games along with the progress.
+pub fn stage_page(path: &Path, page: u32, games: &[Game]) -> Result<(), Error> {
+    let mut conn = open(path)?;
     let tx = conn.transaction()?;
-    let now = Local::now();
-    for game in games {
-        tx.execute("insert into games (id, name, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, page, temp_n, temp_r) 
-        values (?1, ?2, ?3, 0, ?4, ?5, ?6, 1, 0, 0)",
-            &[&game.id as &ToSql, &game.name, &now.to_string(), &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating])?;
+    for (position, game) in games.iter().enumerate() {
+        // bgg could move a game to the next page while we pull, the first place wins
+        tx.execute("insert or ignore into staged_games (id, name, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, page, position)
+            values (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            &[&game.id as &dyn ToSql, &game.name, &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating,
+            &page, &(position as u32)])?;
     }
+    tx.execute("update pull_state set page = ?1", &[&page as &dyn ToSql])?;
     tx.commit()?;
     Ok(())
 }
 
-pub fn get_all_games() -> Result<Vec<Game>, Error> {
-    let conn = Connection::open(DB_FILE_NAME)?;
-    let mut stmt = conn.prepare("SELECT id, name, rating, num_votes, bgg_num_votes, bgg_geek_rating, bgg_avg_rating FROM games order by rating desc")?;
-    let games_iter = stmt
-        .query_map(NO_PARAMS, |row| Game {
-            id: row.get(0),
-            name: row.get(1),
-            rating: row.get(2),
-            votes: row.get(3),
-            bgg_num_votes: row.get(4),
-            bgg_geek_rating: row.get(5),
-            bgg_avg_rating: row.get(6)
-        })?;
+/// Swaps staged list into games at once.
+pub fn finish_pull(path: &Path) -> Result<PullDiff, Error> {
+    let mut conn = open(path)?;
+    let tx = conn.transaction()?;
+    let mut games = Vec::new();
+    {
+        let mut stmt = tx.prepare("select id, name, bgg_num_votes, bgg_geek_rating, bgg_avg_rating from staged_games
+            order by page, position")?;
+        let rows = stmt.query_map(NO_PARAMS, read_staged)?;
+        for row in rows {
+            games.push(row?);
+        }
+    }
+    let diff = upsert_games(&tx, &games)?;
+    tx.execute("delete from staged_games", NO_PARAMS)?;
+    tx.execute("delete from pull_state", NO_PARAMS)?;
+    tx.commit()?;
+    Ok(diff)
+}
+
+/// Replaces the game list with pulled one, balancing state of known games is kept,
+/// missing games are flagged as dropped.
+fn upsert_games(tx: &Transaction, games: &[Game]) -> Result<PullDiff, Error> {
+    let mut known = HashMap::new();
+    {
+        let mut stmt = tx.prepare("select id, name, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, dropped from games")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| (Game {
+            bgg_num_votes: r.get(2),
+            bgg_geek_rating: r.get(3),
+            bgg_avg_rating: r.get(4),
+            ..Game::new(r.get(0), r.get(1))
+        }, r.get::<_, bool>(5)))?;
+        for row in rows {
+            let (game, dropped) = row?;
+            known.insert(game.id, (game, dropped));
+        }
+    }
+    let now = Local::now();
+    let mut diff = PullDiff { new: Vec::new(), dropped: Vec::new(), changed: Vec::new() };
+    let mut pulled = HashSet::new();
+    for game in games {
+        if !pulled.insert(game.id) {
+            continue;
+        }
+        match known.get(&game.id) {
+            None => {
+                tx.execute("insert into games (id, name, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating, page)
+                    values (?1, ?2, ?3, 0, ?4, ?5, ?6, 1)",
+                    &[&game.id as &dyn ToSql, &game.name, &now.to_string(), &game.bgg_num_votes, &game.bgg_geek_rating,
+                    &game.bgg_avg_rating])?;
+                diff.new.push(game.name.clone());
+                continue;
+            },
+            Some((_, true)) => diff.new.push(game.name.clone()),
+            Some((old, false)) => if old.name != game.name || old.bgg_num_votes != game.bgg_num_votes
+                    || old.bgg_geek_rating != game.bgg_geek_rating || old.bgg_avg_rating != game.bgg_avg_rating {
+                diff.changed.push(game.name.clone());
+            }
+        }
+        tx.execute("update games set name = ?1, bgg_num_votes = ?2, bgg_geek_rating = ?3, bgg_avg_rating = ?4, dropped = 0
+            where id = ?5",
+            &[&game.name as &dyn ToSql, &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating, &game.id])?;
+    }
+    for (id, (game, dropped)) in &known {
+        if !dropped && !pulled.contains(id) {
+            tx.execute("update games set dropped = 1 where id = ?1", &[id as &dyn ToSql])?;
+            diff.dropped.push(game.name.clone());
+        }
+    }
+    Ok(diff)
+}
+
+fn read_game(row: &Row) -> Game {
+    Game {
+        id: row.get(0),
+        name: row.get(1),
+        rating: row.get(2),
+        bayes: row.get(3),
+        votes: row.get(4),
+        weighted_rating: row.get(5),
+        weighted_votes: row.get(6),
+        normalized_rating: row.get(7),
+        normalized_votes: row.get(8),
+        bgg_num_votes: row.get(9),
+        bgg_geek_rating: row.get(10),
+        bgg_avg_rating: row.get(11)
+    }
+}
+
+pub fn get_all_games(path: &Path) -> Result<Vec<Game>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM games WHERE not dropped order by rating desc", GAME_COLUMNS))?;
+    let games_iter = stmt.query_map(NO_PARAMS, read_game)?;
     let mut games = Vec::new();
     for game in games_iter {
         games.push(game?);
@@ -77,16 +202,84 @@ pub fn get_all_games() -> Result<Vec<Game>, Error> {
     Ok(games)
 }
 
+/// Saves games with config they were scored by, all or nothing.
+pub fn save_snapshot(path: &Path, label: Option<&str>, config: &str, games: &[Game]) -> Result<Snapshot, Error> {
+    let mut conn = open(path)?;
+    let tx = conn.transaction()?;
+    if let Some(label) = label {
+        let mut stmt = tx.prepare("select count(*) from snapshots where label = ?1")?;
+        let count: u32 = stmt.query_row(&[&label as &dyn ToSql], |r| r.get(0))?;
+        if count > 0 {
+            return Err(Error::Snapshot(format!("label {} is already taken", label)));
+        }
+    }
+    let taken = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    tx.execute("insert into snapshots (label, taken, config) values (?1, ?2, ?3)",
+        &[&label as &dyn ToSql, &taken, &config])?;
+    let id = tx.last_insert_rowid() as u32;
+    for game in games {
+        tx.execute("insert into snapshot_games (snapshot_id, game_id, name, rating, bayes, num_votes,
+            weighted_rating, weighted_votes, normalized_rating, normalized_votes, bgg_num_votes, bgg_geek_rating,
+            bgg_avg_rating) values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            &[&id as &dyn ToSql, &game.id, &game.name, &game.rating, &game.bayes, &game.votes,
+            &game.weighted_rating, &game.weighted_votes, &game.normalized_rating, &game.normalized_votes,
+            &game.bgg_num_votes, &game.bgg_geek_rating, &game.bgg_avg_rating])?;
+    }
+    tx.commit()?;
+    Ok(Snapshot { id, label: label.map(String::from), taken, games: games.len() as u32 })
+}
+
+fn read_snapshot(r: &Row) -> Snapshot {
+    Snapshot { id: r.get(0), label: r.get(1), taken: r.get(2), games: r.get(3) }
+}
+
+pub fn get_snapshots(path: &Path) -> Result<Vec<Snapshot>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(&format!("select {} from snapshots s order by s.id", SNAPSHOT_COLUMNS))?;
+    let rows = stmt.query_map(NO_PARAMS, read_snapshot)?;
+    let mut snapshots = Vec::new();
+    for row in rows {
+        snapshots.push(row?);
+    }
+    Ok(snapshots)
+}
+
+/// Snapshot by label or by id, label wins.
+pub fn find_snapshot(path: &Path, key: &str) -> Result<Option<Snapshot>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare(&format!("select {} from snapshots s where s.label = ?1 or cast(s.id as text) = ?1
+        order by s.label = ?1 desc limit 1", SNAPSHOT_COLUMNS))?;
+    match stmt.query_row(&[&key as &dyn ToSql], read_snapshot) {
+        Ok(snapshot) => Ok(Some(snapshot)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+pub fn get_snapshot_games(path: &Path, snapshot: &Snapshot) -> Result<Vec<Game>, Error> {
+    let conn = open(path)?;
+    let mut stmt = conn.prepare("select game_id, name, rating, bayes, num_votes, weighted_rating, weighted_votes,
+        normalized_rating, normalized_votes, bgg_num_votes, bgg_geek_rating, bgg_avg_rating
+        from snapshot_games where snapshot_id = ?1")?;
+    let rows = stmt.query_map(&[&snapshot.id as &dyn ToSql], read_game)?;
+    let mut games = Vec::new();
+    for row in rows {
+        games.push(row?);
+    }
+    Ok(games)
+}
+
 pub struct DbConn {
     conn: Connection
 }
 
 impl DbConn {
-    pub fn new() -> Result<DbConn, Error> {
-        let conn = Connection::open_with_flags(
-            DB_FILE_NAME,
+    pub fn new(path: &Path) -> Result<DbConn, Error> {
+        let mut conn = Connection::open_with_flags(
+            path,
             OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX // for multi thread
             )?;
+        schema::migrate(&mut conn)?;
         Ok(DbConn { conn })
     }
 
@@ -96,42 +289,194 @@ impl DbConn {
         Ok(count)
     }
 
-    pub fn get_unstable_user(&self) -> Result<Option<User>, Error> {
-        let mut stmt = self.conn.prepare("select name from users where not stable limit 1")?;
-        let user: Option<User> = match stmt.query_row(NO_PARAMS, |r| r.get(0)) {
+    /// Unstable user nobody else works on, the one already claimed by
+    /// the worker comes first, e.g. after a failed request.
+    pub fn claim_user(&self, worker: u32) -> Result<Option<User>, Error> {
+        // single statement, so two workers can't claim the same user
+        self.conn.execute("update users set claimed_by = ?1 where name = (
+            select name from users where not stable and claimed_by is null limit 1)
+            and not exists (select 1 from users where not stable and claimed_by = ?1)", &[&worker as &dyn ToSql])?;
+        let mut stmt = self.conn.prepare("select name from users where not stable and claimed_by = ?1 limit 1")?;
+        let user: Option<User> = match stmt.query_row(&[&worker as &dyn ToSql], |r| r.get(0)) {
             Ok(u) => Some(u),
             Err(rusqlite::Error::QueryReturnedNoRows) => None,
-            Err(e) => bail!(e)
+            Err(e) => return Err(e.into())
         };
         Ok(user)
     }
 
-    pub fn update_user(&self, user: &User, trusted: bool) -> Result<(), Error> {
+    pub fn update_user(&self, user: &User, trusted: bool, stats: Option<UserStats>, policy: &str) -> Result<(), Error> {
         let now = Local::now();
-        match self.conn.execute("UPDATE users SET stable = 1, trusted = ?1, updated =?2 WHERE name = ?3",
-                &[&trusted as &ToSql, &now.to_string() ,user]) {
+        // average of nothing would skew the population
+        let average = stats.filter(|s| !s.is_empty()).map(|s| s.average);
+        let std_dev = stats.and_then(|s| s.std_dev);
+        let ratings = stats.and_then(|s| s.ratings);
+        match self.conn.execute("UPDATE users SET stable = 1, trusted = ?1, updated = ?2, average = ?3, std_dev = ?4, ratings = ?5,
+                policy = ?6, claimed_by = null WHERE name = ?7",
+                &[&trusted as &dyn ToSql, &now.to_string(), &average, &std_dev, &ratings, &policy, user]) {
             Ok(_) => Ok(()),
-            Err(err) => bail!(err)
+            Err(err) => Err(err.into())
+        }
+    }
+
+    /// Forgets claims left by workers of an interrupted run.
+    pub fn release_users(&self) -> Result<(), Error> {
+        self.conn.execute("update users set claimed_by = null where claimed_by is not null", NO_PARAMS)?;
+        Ok(())
+    }
+
+    /// Users with known average, i.e. everyone trust policy can judge.
+    pub fn get_judged_users(&self) -> Result<Vec<(User, UserStats)>, Error> {
+        let mut stmt = self.conn.prepare("select name, average, ratings, std_dev from users
+            where stable and average is not null")?;
+        let rows = stmt.query_map(NO_PARAMS,
+            |r| (r.get(0), UserStats { average: r.get(1), ratings: r.get(2), std_dev: r.get(3) }))?;
+        let mut users = Vec::new();
+        for row in rows {
+            users.push(row?);
+        }
+        Ok(users)
+    }
+
+    /// Known averages of every user, sorted.
+    pub fn get_averages(&self) -> Result<Vec<f64>, Error> {
+        let mut stmt = self.conn.prepare("select average from users where average is not null order by average")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| r.get(0))?;
+        let mut averages = Vec::new();
+        for row in rows {
+            averages.push(row?);
+        }
+        Ok(averages)
+    }
+
+    /// Changes trust of judged users, returns how many users changed it.
+    pub fn set_trust(&mut self, verdicts: &[(User, bool)], policy: &str) -> Result<u32, Error> {
+        let tx = self.conn.transaction()?;
+        let mut changed = 0;
+        for (user, trusted) in verdicts {
+            changed += tx.execute("UPDATE users SET trusted = ?1 WHERE name = ?2 AND trusted != ?1",
+                &[trusted as &dyn ToSql, user])?;
+            tx.execute("UPDATE users SET policy = ?1 WHERE name = ?2", &[&policy as &dyn ToSql, user])?;
+        }
+        tx.commit()?;
+        Ok(changed as u32)
+    }
+
+    /// Mean of averages of judged users.
+    pub fn population_mean(&self) -> Result<Option<f64>, Error> {
+        let mut stmt = self.conn.prepare("select avg(average) from users where average is not null")?;
+        let mean: Option<f64> = stmt.query_row(NO_PARAMS, |r| r.get(0))?;
+        Ok(mean)
+    }
+
+    /// Mean of averages and mean spread of users with known spread.
+    pub fn population_scale(&self) -> Result<Option<(f64, f64)>, Error> {
+        let mut stmt = self.conn.prepare("select avg(average), avg(std_dev) from users where std_dev > 0")?;
+        let scale: (Option<f64>, Option<f64>) = stmt.query_row(NO_PARAMS, |r| (r.get(0), r.get(1)))?;
+        match scale {
+            (Some(mean), Some(spread)) => Ok(Some((mean, spread))),
+            _ => Ok(None)
         }
     }
 
+    /// Share of judged users with lower average, in percents.
+    /// None if nobody has been judged yet.
+    pub fn average_percentile(&self, average: f64) -> Result<Option<f64>, Error> {
+        let mut stmt = self.conn.prepare("select count(*), total(average < ?1) from users where average is not null")?;
+        let (n, below): (u32, f64) = stmt.query_row(&[&average as &dyn ToSql], |r| (r.get(0), r.get(1)))?;
+        if n == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(below * 100.0 / f64::from(n)))
+        }
+    }
+
+    pub fn review_users(&mut self, trusted_for: u32, untrusted_for: u32) -> Result<(u32, u32), Error> {
+        let tx = self.conn.transaction()?;
+        let trusted_for = format!("-{} days", trusted_for);
+        let untrusted_for = format!("-{} days", untrusted_for);
+        let users = tx.execute("UPDATE users SET stable = 0 WHERE stable AND (
+                (trusted AND julianday(updated) < julianday('now', ?1)) OR
+                (NOT trusted AND julianday(updated) < julianday('now', ?2)))",
+            &[&trusted_for as &dyn ToSql, &untrusted_for])?;
+        // stable games have no other unstable users, they are rescored from saved ratings
+        let mut games = tx.execute("UPDATE games SET stable = 0, rescore = 1 WHERE stable AND id IN (
+                SELECT game_id FROM ratings WHERE user IN (SELECT name FROM users WHERE NOT stable))", NO_PARAMS)?;
+        // games balanced before ratings were saved could be rated by anyone, they start over
+        if users > 0 {
+            games += tx.execute("UPDATE games SET stable = 0, page = 1, rescore = 0 WHERE stable AND NOT EXISTS (
+                    SELECT 1 FROM ratings WHERE game_id = games.id)", NO_PARAMS)?;
+        }
+        tx.commit()?;
+        Ok((users as u32, games as u32))
+    }
+
+    pub fn get_progress(&self) -> Result<Progress, Error> {
+        let page_size = bgg::USER_PAGE_SIZE;
+        // stable games keep their last page, unstable are yet to process the current one
+        let mut stmt = self.conn.prepare("select count(*), coalesce(sum(stable), 0),
+            coalesce(sum(case when stable then page else page - 1 end), 0),
+            coalesce(sum(case when stable then 0 else max((bgg_num_votes + ?1 - 1) / ?1 - page + 1, 1) end), 0)
+            from games where not dropped")?;
+        let (games, stable_games, pages, pages_left): (u32, u32, u32, u32) =
+            stmt.query_row(&[&page_size as &dyn ToSql], |r| (r.get(0), r.get(1), r.get(2), r.get(3)))?;
+        let mut stmt = self.conn.prepare("select count(*), coalesce(sum(stable), 0), coalesce(sum(stable and trusted), 0)
+            from users")?;
+        let (users, stable_users, trusted_users): (u32, u32, u32) =
+            stmt.query_row(NO_PARAMS, |r| (r.get(0), r.get(1), r.get(2)))?;
+        Ok(Progress { games, stable_games, users, stable_users, trusted_users, pages, pages_left })
+    }
+
+    /// Number of games balanced during the last day and seconds between the first and the last of them.
+    pub fn get_recent_pace(&self) -> Result<(u32, f64), Error> {
+        let mut stmt = self.conn.prepare("select count(*), coalesce((julianday(max(updated)) - julianday(min(updated))) * 86400, 0)
+            from games where stable and not dropped and julianday(updated) > julianday('now', '-1 day')")?;
+        let pace: (u32, f64) = stmt.query_row(NO_PARAMS, |r| (r.get(0), r.get(1)))?;
+        Ok(pace)
+    }
+
     pub fn get_number_of_unstable_games(&self) -> Result<u32, Error> {
-        let mut stmt = self.conn.prepare("select count(*) from games where not stable")?;
+        let mut stmt = self.conn.prepare("select count(*) from games where not stable and not dropped")?;
         let count: u32 = stmt.query_row(NO_PARAMS, |r| r.get(0))?;
         Ok(count)
     }
 
-    pub fn get_unstable_game(&self) -> Result<Option<(Game, Temp)>, Error> {
-        let mut stmt = self.conn.prepare("select id, name, page, temp_n, temp_r from games where not stable order by random() limit 1")?;
-        let gamebox: Option<(Game, Temp)> = match stmt.query_row(NO_PARAMS,
-                |r| (Game::new(r.get(0), r.get(1)), Temp::new(r.get(2), r.get(3), r.get(4)))) {
+    /// Unstable game, the first page of ratings that was not saved yet
+    /// and whether every page is saved and game only has to be scored again.
+    pub fn get_unstable_game(&self) -> Result<Option<(Game, u32, bool)>, Error> {
+        let mut stmt = self.conn.prepare("select id, name, page, rescore from games where not stable and not dropped
+            order by random() limit 1")?;
+        let gamebox: Option<(Game, u32, bool)> = match stmt.query_row(NO_PARAMS,
+                |r| (Game::new(r.get(0), r.get(1)), r.get(2), r.get(3))) {
             Ok(req) => Some(req),
             Err(rusqlite::Error::QueryReturnedNoRows) => None,
-            Err(e) => bail!(e)
+            Err(e) => return Err(e.into())
         };
         Ok(gamebox)
     }
 
+    /// Balanced games with their scores.
+    pub fn get_balanced_games(&self) -> Result<Vec<Game>, Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM games WHERE stable and not dropped", GAME_COLUMNS))?;
+        let rows = stmt.query_map(NO_PARAMS, read_game)?;
+        let mut games = Vec::new();
+        for row in rows {
+            games.push(row?);
+        }
+        Ok(games)
+    }
+
+    /// Games in progress along with the page of ratings they are on.
+    pub fn get_pending_games(&self) -> Result<Vec<Pending>, Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {}, page FROM games WHERE not stable and not dropped", GAME_COLUMNS))?;
+        let rows = stmt.query_map(NO_PARAMS, |r| (read_game(r), r.get(12)))?;
+        let mut games = Vec::new();
+        for row in rows {
+            games.push(row?);
+        }
+        Ok(games)
+    }
+
     pub fn add_users(&mut self, users: &[&User]) -> Result<(), Error> {
         let tx = self.conn.transaction()?;
         let now = Local::now();
@@ -144,32 +489,160 @@ impl DbConn {
         Ok(())
     }
 
-    pub fn check_user(&self, user: &User) -> Result<Option<bool>, Error> {
-        type Row = (bool, bool); // use to get rid of ugly r.get::<_,bool>(0)
-        let mut stmt = self.conn.prepare("select stable, trusted from users where name = ?")?;
-        let result:Option<bool> = match stmt.query_row(&[user as &ToSql], |r| -> Row { (r.get(0), r.get(1)) }) {
-            Err(e) => bail!(e),
-            Ok((false, _)) => None, // Unstable
-            Ok((_, true)) => Some(true), // trusted
-            Ok((_, false)) => Some(false) // not trusted
+    pub fn add_ratings(&mut self, game: &Game, ratings: &[Rating]) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        let now = Local::now();
+        for r in ratings {
+            tx.execute("insert or replace into ratings (game_id, user, rating, comment, fetched_at) values (?1, ?2, ?3, ?4, ?5)",
+                &[&game.id as &dyn ToSql, &r.user, &r.rating, &r.comment, &now.to_string()])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn drop_ratings(&self, game: &Game) -> Result<(), Error> {
+        self.conn.execute("delete from ratings where game_id = ?1", &[&game.id as &dyn ToSql])?;
+        Ok(())
+    }
+
+    /// Whether some users who rated the game are not judged yet.
+    pub fn has_unstable_raters(&self, game: &Game) -> Result<bool, Error> {
+        let mut stmt = self.conn.prepare("select count(*) from ratings r join users u on u.name = r.user
+            where r.game_id = ?1 and not u.stable")?;
+        let count: u32 = stmt.query_row(&[&game.id as &dyn ToSql], |r| r.get(0))?;
+        Ok(count > 0)
+    }
+
+    /// Saved ratings of a game along with stable users who gave them.
+    pub fn get_ratings(&self, game: &Game) -> Result<Vec<(f64, Verdict)>, Error> {
+        let mut stmt = self.conn.prepare("select r.rating, u.trusted, u.average, u.std_dev from ratings r
+            join users u on u.name = r.user where r.game_id = ?1 and u.stable")?;
+        let rows = stmt.query_map(&[&game.id as &dyn ToSql],
+            |r| (r.get(0), Verdict { trusted: r.get(1), average: r.get(2), std_dev: r.get(3) }))?;
+        let mut ratings = Vec::new();
+        for row in rows {
+            ratings.push(row?);
+        }
+        Ok(ratings)
+    }
+
+    pub fn check_user(&self, user: &User) -> Result<Option<Verdict>, Error> {
+        type Row = (bool, bool, Option<f64>, Option<f64>); // use to get rid of ugly r.get::<_,bool>(0)
+        let mut stmt = self.conn.prepare("select stable, trusted, average, std_dev from users where name = ?")?;
+        let result: Option<Verdict> = match stmt.query_row(&[user as &dyn ToSql],
+                |r| -> Row { (r.get(0), r.get(1), r.get(2), r.get(3)) }) {
+            Err(e) => return Err(e.into()),
+            Ok((false, _, _, _)) => None, // Unstable
+            Ok((true, trusted, average, std_dev)) => Some(Verdict { trusted, average, std_dev })
         };
         Ok(result)
     }
 
-    pub fn update_game(&self, game: &Game) -> Result<(), Error> {
+    pub fn update_game(&self, game: &Game, policy: &str) -> Result<(), Error> {
         let now = Local::now();
-        match self.conn.execute("UPDATE games SET stable = 1, rating = ?1, num_votes = ?2, updated = ?3 WHERE id = ?4",
-                &[&game.rating as &ToSql, &game.votes, &now.to_string(), &game.id]) {
+        match self.conn.execute("UPDATE games SET stable = 1, rescore = 0, rating = ?1, bayes = ?2, num_votes = ?3,
+                weighted_rating = ?4, weighted_votes = ?5, normalized_rating = ?6, normalized_votes = ?7,
+                updated = ?8, policy = ?9 WHERE id = ?10",
+                &[&game.rating as &dyn ToSql, &game.bayes, &game.votes, &game.weighted_rating, &game.weighted_votes,
+                &game.normalized_rating, &game.normalized_votes, &now.to_string(), &policy, &game.id]) {
             Ok(_) => Ok(()),
-            Err(err) => bail!(err)
+            Err(err) => Err(err.into())
         }
     }
 
-    pub fn update_page(&self, game: &Game, new_page: u32, temp_n: u32, temp_r: f64) -> Result<(), Error> {
-        match self.conn.execute("UPDATE games SET page = ?1, temp_n = ?2, temp_r = ?3 WHERE id = ?4",
-                &[&new_page as &ToSql, &temp_n, &temp_r, &game.id]) {
+    pub fn get_stable_games(&self) -> Result<Vec<Game>, Error> {
+        // games balanced before ratings were saved keep their scores
+        let mut stmt = self.conn.prepare("select id, name from games
+            where stable and exists (select 1 from ratings where game_id = games.id)")?;
+        let rows = stmt.query_map(NO_PARAMS, |r| Game::new(r.get(0), r.get(1)))?;
+        let mut games = Vec::new();
+        for row in rows {
+            games.push(row?);
+        }
+        Ok(games)
+    }
+
+    /// Games balanced before ratings were saved, with their old scores.
+    pub fn get_legacy_games(&self) -> Result<Vec<Game>, Error> {
+        let mut stmt = self.conn.prepare(&format!("SELECT {} FROM games
+            WHERE stable and not exists (select 1 from ratings where game_id = games.id)", GAME_COLUMNS))?;
+        let rows = stmt.query_map(NO_PARAMS, read_game)?;
+        let mut games = Vec::new();
+        for row in rows {
+            games.push(row?);
+        }
+        Ok(games)
+    }
+
+    pub fn save_bayes(&mut self, games: &[Game]) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        for game in games {
+            tx.execute("UPDATE games SET bayes = ?1 WHERE id = ?2", &[&game.bayes as &dyn ToSql, &game.id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Overwrites scores only, games keep their stability and update time.
+    pub fn save_scores(&mut self, games: &[Game], policy: &str) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        for game in games {
+            tx.execute("UPDATE games SET rating = ?1, bayes = ?2, num_votes = ?3, weighted_rating = ?4, weighted_votes = ?5,
+                normalized_rating = ?6, normalized_votes = ?7, policy = ?8 WHERE id = ?9",
+                &[&game.rating as &dyn ToSql, &game.bayes, &game.votes, &game.weighted_rating, &game.weighted_votes,
+                &game.normalized_rating, &game.normalized_votes, &policy, &game.id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn update_page(&self, game: &Game, new_page: u32) -> Result<(), Error> {
+        match self.conn.execute("UPDATE games SET page = ?1 WHERE id = ?2",
+                &[&new_page as &dyn ToSql, &game.id]) {
             Ok(_) => Ok(()),
-            Err(err) => bail!(err)
+            Err(err) => Err(err.into())
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn fresh_db(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("bgg_swing_{}_{}.db", name, process::id()));
+        fs::remove_file(&path).unwrap_or_default();
+        initialize(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn review_sends_back_games_of_expired_users() {
+        let path = fresh_db("review");
+        let mut conn = DbConn::new(&path).unwrap();
+        conn.conn.execute_batch("
+            insert into users (name, updated, stable, trusted) values ('old', '2000-01-01', 1, 1);
+            insert into users (name, updated, stable, trusted) values ('new', datetime('now'), 1, 1);
+            insert into games (id, name, rating, stable, page) values (1, 'Saved', 7.0, 1, 2);
+            insert into games (id, name, rating, stable, page) values (2, 'Legacy', 6.0, 1, 2);
+            insert into games (id, name, rating, stable, page) values (3, 'Fresh', 5.0, 1, 2);
+            insert into ratings (game_id, user, rating) values (1, 'old', 7.0);
+            insert into ratings (game_id, user, rating) values (1, 'new', 7.0);
+            insert into ratings (game_id, user, rating) values (3, 'new', 5.0);").unwrap();
+        assert_eq!(conn.review_users(180, 90).unwrap(), (1, 2));
+        let state = |id: u32| -> (bool, u32, bool) {
+            conn.conn.query_row("select stable, page, rescore from games where id = ?1", &[&id as &dyn ToSql],
+                |r| (r.get(0), r.get(1), r.get(2))).unwrap()
+        };
+        // saved ratings are kept and only wait for the user
+        assert_eq!(state(1), (false, 2, true));
+        // nothing to rescore from, ratings are pulled again
+        assert_eq!(state(2), (false, 1, false));
+        assert_eq!(state(3), (true, 2, false));
+        fs::remove_file(&path).unwrap_or_default();
+    }
+}
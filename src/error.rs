@@ -0,0 +1,86 @@
+use failure::Fail;
+use reqwest::StatusCode;
+use std::fmt;
+use std::io;
+use std::num::{ParseFloatError, ParseIntError};
+use crate::bgg::Status;
+
+#[derive(Debug)]
+pub enum Error {
+    Network { url: String, reason: String }, // request was not sent or answer was not read
+    Http { url: String, code: StatusCode, status: Status }, // bgg answered, but not with 200
+    Parse(String), // bgg page does not look as expected, layout has changed
+    Db(rusqlite::Error),
+    Schema(String), // db was made by a newer version
+    Config(String),
+    Io { path: String, cause: io::Error },
+    Cassette(String), // nothing to replay
+    Snapshot(String), // no such snapshot or label is unusable
+    Exhausted // thread has seen too many failures in a row
+}
+
+impl Error {
+    pub fn http(url: &str, code: StatusCode) -> Error {
+        let status = Status::of(code).unwrap_or(Status::Rejected);
+        Error::Http { url: url.to_string(), code, status }
+    }
+
+    pub fn io(path: impl fmt::Display, cause: io::Error) -> Error {
+        Error::Io { path: path.to_string(), cause }
+    }
+
+    /// Asking bgg again later could help.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Network { .. } => true,
+            // gone and rejected requests fail the same way every time
+            Error::Http { status, .. } => !matches!(status, Status::Gone | Status::Rejected),
+            _ => false
+        }
+    }
+
+    /// Bgg will never know about it, no reason to ask again.
+    pub fn is_gone(&self) -> bool {
+        match self {
+            Error::Http { status, .. } => *status == Status::Gone,
+            _ => false
+        }
+    }
+}
+
+impl Fail for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Network { url, reason } => write!(f, "Could not download `{}`: {}", url, reason),
+            Error::Http { url, code, .. } => write!(f, "Request to `{}` failed. Status: {}", url, code),
+            Error::Parse(msg) => write!(f, "Can't parse bgg page. {}", msg),
+            Error::Db(e) => write!(f, "Database error: {}", e),
+            Error::Schema(msg) => write!(f, "Unknown database schema: {}", msg),
+            Error::Config(msg) => write!(f, "Config error: {}", msg),
+            Error::Io { path, cause } => write!(f, "Can't access {}: {}", path, cause),
+            Error::Cassette(msg) => write!(f, "{}", msg),
+            Error::Snapshot(msg) => write!(f, "Snapshot error: {}", msg),
+            Error::Exhausted => write!(f, "Regulation token stopped the process")
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Db(e)
+    }
+}
+
+impl From<ParseFloatError> for Error {
+    fn from(e: ParseFloatError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(e: ParseIntError) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
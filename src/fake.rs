@@ -0,0 +1,159 @@
+use reqwest::StatusCode;
+use serde_derive::Deserialize;
+use serde_json::{from_str, from_value, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use crate::bgg::BggSource;
+use crate::error::Error;
+use crate::lib::{Game, User, UserStats, Rating};
+
+/// Scripted answer to a single request.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+enum Reply<T> {
+    Ok(T),
+    Error(String), // network failure
+    Broken(String), // page with unexpected layout
+    Throttled, // 429 Too Many Requests
+    Gone // 404 Not Found
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct FakeGame {
+    id: u32,
+    name: String,
+    #[serde(default)]
+    bgg_num_votes: u32,
+    #[serde(default)]
+    bgg_geek_rating: f64,
+    #[serde(default)]
+    bgg_avg_rating: f64
+}
+
+impl From<FakeGame> for Game {
+    fn from(g: FakeGame) -> Game {
+        Game {
+            bgg_num_votes: g.bgg_num_votes,
+            bgg_geek_rating: g.bgg_geek_rating,
+            bgg_avg_rating: g.bgg_avg_rating,
+            ..Game::new(g.id, g.name)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct FakeUser {
+    average: f64,
+    #[serde(default)]
+    ratings: Option<u32>,
+    #[serde(default)]
+    std_dev: Option<f64>
+}
+
+/// [user, rating] or [user, rating, comment]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum FakeRating {
+    Plain(User, f64),
+    Commented(User, f64, String)
+}
+
+impl From<FakeRating> for Rating {
+    fn from(r: FakeRating) -> Rating {
+        match r {
+            FakeRating::Plain(user, rating) => Rating { user, rating, comment: None },
+            FakeRating::Commented(user, rating, comment) => Rating { user, rating, comment: Some(comment) }
+        }
+    }
+}
+
+type Replies<T> = VecDeque<Reply<T>>;
+type Pages<T> = BTreeMap<u32, Replies<T>>;
+
+/// Replies are given in order, the last one is repeated forever.
+/// Missing pages are empty, missing users are gone.
+#[derive(Debug, Deserialize)]
+struct Script {
+    #[serde(default)]
+    games: Pages<Vec<FakeGame>>, // page -> replies
+    #[serde(default)]
+    ratings: BTreeMap<u32, Pages<Vec<FakeRating>>>, // game -> page -> replies
+    #[serde(default)]
+    users: BTreeMap<User, Replies<FakeUser>> // user -> replies
+}
+
+/// In-memory bgg, answers with scripted data instead of network.
+pub struct FakeBgg {
+    script: Mutex<Script>
+}
+
+impl FakeBgg {
+    pub fn from_file(path: &Path) -> Result<FakeBgg, Error> {
+        let script = fs::read_to_string(path)
+            .map_err(|e| Error::io(path.display(), e))?;
+        from_str(&script).and_then(FakeBgg::from_value)
+            .map_err(|e| Error::Config(format!("Can't parse script {}: {}", path.display(), e)))
+    }
+
+    /// Script in the same shape as the file, e.g. built with json! in tests.
+    pub fn from_value(script: Value) -> Result<FakeBgg, serde_json::Error> {
+        Ok(FakeBgg { script: Mutex::new(from_value(script)?) })
+    }
+}
+
+fn next<T: Clone>(replies: Option<&mut Replies<T>>) -> Option<Reply<T>> {
+    let replies = replies?;
+    if replies.len() > 1 {
+        replies.pop_front()
+    } else {
+        replies.front().cloned()
+    }
+}
+
+fn answer<T>(reply: Reply<T>, request: &str) -> Result<T, Error> {
+    match reply {
+        Reply::Ok(data) => Ok(data),
+        Reply::Error(e) => Err(Error::Network { url: request.to_string(), reason: e }),
+        Reply::Broken(e) => Err(Error::Parse(e)),
+        Reply::Throttled => Err(Error::http(request, StatusCode::TOO_MANY_REQUESTS)),
+        Reply::Gone => Err(Error::http(request, StatusCode::NOT_FOUND))
+    }
+}
+
+impl BggSource for FakeBgg {
+    fn games(&self, page: u32, _user_limit: u32) -> Result<Vec<Game>, Error> {
+        let mut script = self.script.lock().unwrap();
+        match next(script.games.get_mut(&page)) {
+            None => Ok(Vec::new()),
+            Some(reply) => {
+                let games = answer(reply, &format!("games/{}", page))?;
+                Ok(games.into_iter().map(Game::from).collect())
+            }
+        }
+    }
+
+    fn ratings(&self, game_id: u32, page: u32) -> Result<Vec<Rating>, Error> {
+        let mut script = self.script.lock().unwrap();
+        let replies = script.ratings.get_mut(&game_id).and_then(|pages| pages.get_mut(&page));
+        match next(replies) {
+            None => Ok(Vec::new()),
+            Some(reply) => {
+                let ratings = answer(reply, &format!("ratings/{}/{}", game_id, page))?;
+                Ok(ratings.into_iter().map(Rating::from).collect())
+            }
+        }
+    }
+
+    fn user_stats(&self, user: &User) -> Result<UserStats, Error> {
+        let mut script = self.script.lock().unwrap();
+        match next(script.users.get_mut(user)) {
+            None => Err(Error::http(&format!("users/{}", user), StatusCode::NOT_FOUND)),
+            Some(reply) => {
+                let u = answer(reply, &format!("users/{}", user))?;
+                Ok(UserStats { average: u.average, ratings: u.ratings, std_dev: u.std_dev })
+            }
+        }
+    }
+}
@@ -4,7 +4,12 @@ pub struct Game {
     pub id: u32,
     pub name: String,
     pub rating: f64,
+    pub bayes: f64,
     pub votes: u32,
+    pub weighted_rating: f64,
+    pub weighted_votes: f64,
+    pub normalized_rating: f64,
+    pub normalized_votes: u32,
     pub bgg_num_votes: u32,
     pub bgg_geek_rating: f64,
     pub bgg_avg_rating: f64
@@ -12,20 +17,72 @@ pub struct Game {
 
 impl Game {
     pub fn new(id: u32, name: String) -> Game {
-        Game { id, name, rating: 0.0, votes: 0, bgg_num_votes: 0, bgg_geek_rating: 0.0, bgg_avg_rating: 0.0 }
+        Game { id, name, rating: 0.0, bayes: 0.0, votes: 0, weighted_rating: 0.0, weighted_votes: 0.0,
+            normalized_rating: 0.0, normalized_votes: 0,
+            bgg_num_votes: 0, bgg_geek_rating: 0.0, bgg_avg_rating: 0.0 }
     }
 }
 
 pub type User = String; // user name
 
-pub struct Temp {
-    pub page: u32,
-    pub n: u32,
-    pub r: f64
+pub type Pending = (Game, u32); // game in progress and the page of ratings it is on
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserStats {
+    pub average: f64, // average rating given by user
+    pub ratings: Option<u32>, // number of ratings, if bgg tells
+    pub std_dev: Option<f64> // spread of ratings given by user, if bgg tells
 }
 
-impl Temp {
-    pub fn new(page: u32, n: u32, r: f64) -> Temp {
-        Temp { page, n, r }
+impl UserStats {
+    /// User who has rated nothing, there is no average to judge.
+    pub fn empty() -> UserStats {
+        UserStats { average: 0.0, ratings: Some(0), std_dev: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ratings == Some(0)
     }
 }
+
+/// Stable user as seen by a game.
+pub struct Verdict {
+    pub trusted: bool,
+    pub average: Option<f64>, // None if bgg does not know the user
+    pub std_dev: Option<f64>
+}
+
+/// How far balancing has gone.
+pub struct Progress {
+    pub games: u32,
+    pub stable_games: u32,
+    pub users: u32,
+    pub stable_users: u32,
+    pub trusted_users: u32,
+    pub pages: u32, // rating pages processed
+    pub pages_left: u32 // guess from bgg number of votes
+}
+
+/// What pull changed in the game list, by names.
+pub struct PullDiff {
+    pub new: Vec<String>, // absent or dropped before
+    pub dropped: Vec<String>, // no longer meet the limit
+    pub changed: Vec<String> // bgg data has changed
+}
+
+/// Rating of a game given by a user.
+#[derive(Debug, Clone)]
+pub struct Rating {
+    pub user: User,
+    pub rating: f64,
+    pub comment: Option<String>
+}
+
+/// Frozen report, games are stored with it.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub id: u32,
+    pub label: Option<String>,
+    pub taken: String,
+    pub games: u32
+}
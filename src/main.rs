@@ -2,63 +2,147 @@ mod cli;
 mod core;
 mod db;
 mod bgg;
+mod tape;
+mod fake;
+mod trust;
+mod error;
+mod schema;
+mod report;
 mod lib;
 
-use crate::core::Message;
-use cli::Cli;
+use crate::core::{Message, Workspace};
+use crate::bgg::Status;
+use crate::tape::Tape;
+use crate::fake::FakeBgg;
+use cli::{Cli, Command, ConfigCommand, Net};
 use structopt::StructOpt;
 use failure::Error;
 use exitfailure::ExitFailure;
+use std::fs;
 use std::io::Write;
+use std::path::PathBuf;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use ctrlc;
 
 fn main() -> Result<(), ExitFailure> {
     let cli = Cli::from_args();
-    match cli {
-        Cli::New { } => create_structure()?,
-        Cli::Report { } => make_report()?,
-        Cli::Pull { } => pull_games()?,
-        Cli::Balance { } => stabilize()?,
-        Cli::Review { } => review_users()?
+    let ws = Workspace::new(cli.workspace, cli.db, cli.config);
+    let result = match cli.command {
+        Command::New { } => create_structure(&ws),
+        Command::Report { format, score, sort, output, partial } => make_report(&ws, format, score, sort, output, partial),
+        Command::Pull { resume, net } => pull_games(&ws, resume, net),
+        Command::Balance { net } => stabilize(&ws, net),
+        Command::Recompute { } => recompute(&ws),
+        Command::Status { } => status(&ws),
+        Command::Migrate { dry_run } => migrate(&ws, dry_run),
+        Command::Snapshot { list, label } => snapshot(&ws, list, label),
+        Command::Diff { a, b, score } => diff(&ws, &a, &b, score),
+        Command::Config { command } => config(&ws, command),
+        Command::Review { } => review_users(&ws)
+    };
+    if let Some(e) = result.as_ref().err().and_then(|e| e.downcast_ref::<error::Error>()) {
+        eprintln!("{}", advice(e));
+    }
+    Ok(result?)
+}
+
+fn advice(e: &error::Error) -> &'static str {
+    use crate::error::Error::*;
+    match e {
+        Network { .. } => "Check connection and base url, then run the command again.",
+        Http { status: Status::Queued, .. } => "Bgg is still preparing the answer, increase retry.queued in config file.",
+        Http { status: Status::Throttled, .. } => "Bgg asks to slow down, increase delays or retry.throttled in config file.",
+        Http { status: Status::Transient, .. } => "Bgg is in trouble, try again later.",
+        Http { status: Status::Gone, .. } => "Bgg does not know about it anymore.",
+        Http { status: Status::Rejected, .. } => "Bgg refuses the request, check base url.",
+        Parse(_) => "Bgg layout has probably changed, parser needs an update.",
+        Db(_) => "Check that .db file exists, create it with 'new' command.",
+        Schema(_) => "Update bgg_swing, .db file was migrated by a newer version.",
+        Config(_) => "Fix config file, toml or json if it starts with {, or create a new one with 'new' command.",
+        Io { .. } => "Check that the file exists and can be accessed.",
+        Cassette(_) => "Record cassettes with --record before replaying them.",
+        Snapshot(_) => "List saved snapshots with 'snapshot --list'.",
+        Exhausted => "Too many failures in a row, increase delays or attempts in config file."
     }
-    Ok(())
 }
 
-fn create_structure() -> Result<(), Error> {
-    core::create_structure()?;
+fn create_structure(ws: &Workspace) -> Result<(), Error> {
+    core::create_structure(ws)?;
     println!("Created initial structure files.");
     Ok(())
 }
 
-fn make_report() -> Result<(), Error> {
-    let games = core::make_report()?;
-    if games.is_empty() {
-        println!("Game list is not stable enough.");
+fn make_report(ws: &Workspace, format: report::Format, score: report::Score, sort: report::Sort, output: Option<PathBuf>,
+               partial: bool) -> Result<(), Error> {
+    let text = if partial {
+        let config = core::config(ws)?;
+        let (games, pending) = core::make_partial_report(ws, &config)?;
+        if games.is_empty() && pending.is_empty() {
+            println!("Game list is empty, pull games first.");
+            return Ok(());
+        }
+        report::render(&games, Some(&pending), format, score, sort)
     } else {
-        println!("Id\tName\tRating\tVotes\tGeek Rating\tAvg BGG Rating\tBGG Votes");
-        for game in games {
-            println!("{}\t{}\t{:.2}\t{}\t{}\t{}\t{}",
-                game.id, game.name, game.rating, game.votes,
-                game.bgg_geek_rating, game.bgg_avg_rating, game.bgg_num_votes);
+        let games = core::make_report(ws)?;
+        if games.is_empty() {
+            println!("Game list is not stable enough.");
+            return Ok(());
         }
+        report::render(&games, None, format, score, sort)
+    };
+    match output {
+        Some(path) => {
+            fs::write(&path, text).map_err(|e| error::Error::io(path.display(), e))?;
+            println!("Saved report to {}.", path.display());
+        },
+        None => print!("{}", text)
     }
     Ok(())
 }
 
-fn pull_games() -> Result<(), Error> {
-    let config = core::config()?;
+fn load_config(ws: &Workspace, net: &Net) -> Result<core::Config, Error> {
+    let mut config = core::config(ws)?;
+    if let Some(url) = &net.base_url {
+        config.base_url = url.clone();
+    }
+    Ok(config)
+}
+
+fn source(config: &core::Config, net: Net) -> Result<bgg::Source, Error> {
+    if let Some(script) = net.fake {
+        return Ok(Arc::new(FakeBgg::from_file(&script)?));
+    }
+    let tape = match (net.record, net.replay) {
+        (Some(dir), _) => Tape::Record(dir),
+        (None, Some(dir)) => Tape::Replay(dir),
+        (None, None) => Tape::Off
+    };
+    let limiter = bgg::RateLimiter::new(config.rate_limit);
+    Ok(Arc::new(bgg::Scraper::new(&config.base_url, tape, config.user_method, config.retry, limiter)))
+}
+
+fn pull_games(ws: &Workspace, resume: bool, net: Net) -> Result<(), Error> {
+    let config = load_config(ws, &net)?;
+    let source = source(&config, net)?;
     println!("Starting download.");
-    core::pull_games(config.limit, |i| {
+    let diff = core::pull_games(ws, &config, source.as_ref(), resume, |i| {
         println!("Downloaded page: {}", i);
     })?;
     println!("Finished download.");
+    println!("New: {}, dropped: {}, changed: {}.", diff.new.len(), diff.dropped.len(), diff.changed.len());
+    for name in diff.new {
+        println!("+ {}", name);
+    }
+    for name in diff.dropped {
+        println!("- {}", name);
+    }
     Ok(())
 }
 
-fn stabilize() -> Result<(), Error> {
+fn stabilize(ws: &Workspace, net: Net) -> Result<(), Error> {
     // Cancellation token
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -67,13 +151,19 @@ fn stabilize() -> Result<(), Error> {
         r.store(false, Ordering::SeqCst);
     })?;
     // Load config
-    let config = core::config()?;
+    let config = load_config(ws, &net)?;
+    let source = source(&config, net)?;
+    // trust policy could have changed since users were judged
+    let (users, _, _) = core::recompute(ws, &config)?;
+    if users > 0 {
+        println!("Changed trust of {} users with current config.", users);
+    }
     println!("Start balancing.");
     // Prettify output a bit
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
     let mut seen_users: u32 = 0;
     let mut balanced_games: u32 = 0;
-    core::stabilize(config, running, |m| match m {
+    core::stabilize(ws, config, source, running, |m| match m {
         Message::UserProgress(_) => {
             seen_users += 1;
             if seen_users % 50 == 0 {
@@ -88,7 +178,7 @@ fn stabilize() -> Result<(), Error> {
         },
         Message::Notification(error) => {
             stdout.set_color(ColorSpec::new().set_fg(Some(Color::Red))).unwrap();
-            writeln!(&mut stdout, "{:?}", error).unwrap();
+            writeln!(&mut stdout, "{}", error).unwrap();
         },
         Message::Info(game) => {
             stdout.set_color(ColorSpec::new().set_fg(Some(Color::Green))).unwrap();
@@ -101,9 +191,125 @@ fn stabilize() -> Result<(), Error> {
     Ok(())
 }
 
-fn review_users() -> Result<(), Error> {
-    // TODO: make unstable again. trusted after 180 untrusted 90
-    // any update on user in that mode
-    // makes gametable unbalanced
+fn recompute(ws: &Workspace) -> Result<(), Error> {
+    let config = core::config(ws)?;
+    let (users, games, legacy) = core::recompute(ws, &config)?;
+    println!("Changed trust of {} users and recomputed {} games.", users, games);
+    if legacy > 0 {
+        println!("Skipped {} games balanced before ratings were saved, only their bayes score is recomputed.", legacy);
+    }
+    Ok(())
+}
+
+fn status(ws: &Workspace) -> Result<(), Error> {
+    let config = core::config(ws)?;
+    let (p, eta) = core::status(ws, &config)?;
+    let share = |part: u32, whole: u32| if whole == 0 { 0.0 } else { f64::from(part) * 100.0 / f64::from(whole) };
+    println!("Games: {} balanced, {} unstable.", p.stable_games, p.games - p.stable_games);
+    println!("Users: {} judged, {} unstable.", p.stable_users, p.users - p.stable_users);
+    println!("Trusted: {} of {} judged users ({:.1}%).", p.trusted_users, p.stable_users, share(p.trusted_users, p.stable_users));
+    let per_game = if p.games == 0 { 0.0 } else { f64::from(p.pages) / f64::from(p.games) };
+    println!("Rating pages: {} processed, {:.1} per game, about {} left.", p.pages, per_game, p.pages_left);
+    match eta {
+        core::Eta::Done => println!("Game list is balanced."),
+        core::Eta::Measured(d) => println!("ETA: {}, measured on games balanced during the last day.", duration(d)),
+        core::Eta::Estimated(d) => println!("ETA: {}, estimated from config delays.", duration(d))
+    }
+    Ok(())
+}
+
+fn duration(d: Duration) -> String {
+    let minutes = d.as_secs() / 60;
+    match (minutes / (60 * 24), minutes / 60 % 24, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {}m", h, m),
+        (d, h, m) => format!("{}d {}h {}m", d, h, m)
+    }
+}
+
+fn migrate(ws: &Workspace, dry_run: bool) -> Result<(), Error> {
+    let (version, migrations) = if dry_run {
+        db::pending_migrations(&ws.db)?
+    } else {
+        db::migrate(&ws.db)?
+    };
+    println!("Database is at version {}.", version);
+    if migrations.is_empty() {
+        println!("Nothing to migrate.");
+        return Ok(());
+    }
+    println!("{}", if dry_run { "Would apply:" } else { "Applied:" });
+    for (v, name) in migrations {
+        println!("  {}: {}", v, name);
+    }
+    Ok(())
+}
+
+fn snapshot(ws: &Workspace, list: bool, label: Option<String>) -> Result<(), Error> {
+    if list {
+        let snapshots = core::snapshots(ws)?;
+        if snapshots.is_empty() {
+            println!("There are no snapshots yet.");
+        }
+        for s in snapshots {
+            println!("{}\t{}\t{}\t{} games", s.id, s.label.unwrap_or_default(), s.taken, s.games);
+        }
+        return Ok(());
+    }
+    let config = core::config(ws)?;
+    match core::take_snapshot(ws, &config, label)? {
+        Some(s) => println!("Saved snapshot {} with {} games.", s.label.clone().unwrap_or_else(|| s.id.to_string()), s.games),
+        None => println!("Game list is not stable enough.")
+    }
+    Ok(())
+}
+
+fn diff(ws: &Workspace, a: &str, b: &str, score: report::Score) -> Result<(), Error> {
+    let (old, old_games) = core::load_snapshot(ws, a)?;
+    let (new, new_games) = core::load_snapshot(ws, b)?;
+    println!("From {} taken {} to {} taken {}.", a, old.taken, b, new.taken);
+    let changes = report::compare(&old_games, &new_games, score);
+    let entered = changes.iter().filter(|c| c.before.is_none()).count();
+    let left = changes.iter().filter(|c| c.after.is_none()).count();
+    let moved = changes.iter().filter(|c| c.shift().is_some_and(|s| s != 0)).count();
+    println!("Entered: {}, left: {}, moved: {}.", entered, left, moved);
+    for c in changes {
+        match (c.before, c.after) {
+            (None, Some((rank, s))) => println!("+ {}: rank {}, score {:.2}", c.name, rank, s),
+            (Some((rank, s)), None) => println!("- {}: was rank {}, score {:.2}", c.name, rank, s),
+            (Some((was, before)), Some((rank, after))) if was != rank || (after - before).abs() >= 0.005 =>
+                println!("  {}: rank {} -> {} ({:+}), score {:.2} -> {:.2} ({:+.2})",
+                    c.name, was, rank, i64::from(was) - i64::from(rank), before, after, after - before),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn config(ws: &Workspace, command: ConfigCommand) -> Result<(), Error> {
+    match command {
+        ConfigCommand::Show { } => print!("{}", core::show_config(ws)?),
+        ConfigCommand::Set { key, value } => {
+            core::set_config(ws, &key, &value)?;
+            println!("Set {} in {}.", key, ws.config.display());
+        },
+        ConfigCommand::Validate { } => {
+            let (missing, unknown) = core::check_config(ws)?;
+            for key in missing {
+                println!("Missing {}, default is used.", key);
+            }
+            for key in unknown {
+                println!("Unknown {}, it is ignored.", key);
+            }
+            println!("Config is valid.");
+        }
+    }
+    Ok(())
+}
+
+fn review_users(ws: &Workspace) -> Result<(), Error> {
+    let config = core::config(ws)?;
+    let (users, games) = core::review_users(ws, &config)?;
+    println!("Marked {} users and {} games as unstable.", users, games);
     Ok(())
 }
@@ -0,0 +1,368 @@
+use serde_derive::Serialize;
+use serde_json::to_string_pretty;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use crate::lib::{Game, Pending};
+
+const HEADER: [&str; 15] = ["Rank", "BGG Rank", "Shift", "Id", "Name", "Rating", "Bayes", "Votes",
+    "Weighted", "Weighted Votes", "Normalized", "Normalized Votes",
+    "Geek Rating", "Avg BGG Rating", "BGG Votes"];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Tsv,
+    Csv,
+    Json,
+    Markdown,
+    Html
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "markdown" | "md" => Ok(Format::Markdown),
+            "html" => Ok(Format::Html),
+            _ => Err(format!("Unknown format: {}. Use tsv, csv, json, markdown or html.", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Sort {
+    Rank, // filtered rating
+    Shift // biggest risers first, biggest fallers last
+}
+
+impl FromStr for Sort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Sort, String> {
+        match s {
+            "rank" => Ok(Sort::Rank),
+            "shift" => Ok(Sort::Shift),
+            _ => Err(format!("Unknown sort: {}. Use rank or shift.", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Score {
+    Rating, // plain average of trusted ratings
+    Bayes, // average pulled towards prior mean
+    Weighted, // every rating counts, weighted by user's closeness to population mean
+    Normalized // every rating rescaled by user's own mean and spread
+}
+
+impl Score {
+    fn of(self, game: &Game) -> f64 {
+        match self {
+            Score::Rating => game.rating,
+            Score::Bayes => game.bayes,
+            Score::Weighted => game.weighted_rating,
+            Score::Normalized => game.normalized_rating
+        }
+    }
+}
+
+impl FromStr for Score {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Score, String> {
+        match s {
+            "rating" => Ok(Score::Rating),
+            "bayes" => Ok(Score::Bayes),
+            "weighted" => Ok(Score::Weighted),
+            "normalized" => Ok(Score::Normalized),
+            _ => Err(format!("Unknown score: {}. Use rating, bayes, weighted or normalized.", s))
+        }
+    }
+}
+
+/// Game with its place in filtered and bgg lists.
+struct Ranked<'a> {
+    game: &'a Game,
+    rank: Option<u32>, // None while game is pending
+    bgg_rank: u32,
+    shift: Option<i64>, // positive if game climbs without hype
+    page: Option<u32> // page of ratings pending game is on
+}
+
+// Competition ranking 1224, tied games share the best place.
+fn places(scores: &[f64]) -> Vec<u32> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|&a, &b| scores[b].partial_cmp(&scores[a]).unwrap_or(Ordering::Equal));
+    let mut places = vec![0; scores.len()];
+    for (i, &idx) in order.iter().enumerate() {
+        places[idx] = match i {
+            0 => 1,
+            _ if scores[order[i - 1]] == scores[idx] => places[order[i - 1]],
+            _ => i as u32 + 1
+        };
+    }
+    places
+}
+
+// Pending games get bgg rank only, they follow balanced ones.
+// Bgg ranks balanced games among themselves, so shift is the difference
+// of the two ranks on the row and pending games do not inflate it.
+fn rank<'a>(games: &'a [Game], pending: &'a [Pending], score: Score, sort: Sort) -> Vec<Ranked<'a>> {
+    let scores: Vec<f64> = games.iter().map(|g| score.of(g)).collect();
+    let ranks = places(&scores);
+    let bgg_scores: Vec<f64> = games.iter().map(|g| g.bgg_geek_rating).collect();
+    let bgg_ranks = places(&bgg_scores);
+    let pending_scores: Vec<f64> = pending.iter().map(|(g, _)| g.bgg_geek_rating).collect();
+    let pending_ranks = places(&pending_scores).into_iter().map(|r| r + games.len() as u32);
+    let mut ranked: Vec<Ranked> = games.iter().zip(ranks).zip(bgg_ranks)
+        .map(|((game, rank), bgg_rank)| Ranked {
+            game,
+            rank: Some(rank),
+            bgg_rank,
+            shift: Some(i64::from(bgg_rank) - i64::from(rank)),
+            page: None
+        })
+        .chain(pending.iter().zip(pending_ranks).map(|((game, page), bgg_rank)| Ranked {
+            game,
+            rank: None,
+            bgg_rank,
+            shift: None,
+            page: Some(*page)
+        }))
+        .collect();
+    match sort {
+        Sort::Rank => ranked.sort_by_key(|r| (r.rank.is_none(), r.rank, r.bgg_rank)),
+        Sort::Shift => ranked.sort_by_key(|r| (r.shift.is_none(), r.shift.map(|s| -s), r.rank, r.bgg_rank))
+    }
+    ranked
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    rank: Option<u32>,
+    bgg_rank: u32,
+    shift: Option<i64>,
+    id: u32,
+    name: &'a str,
+    rating: f64,
+    bayes: f64,
+    votes: u32,
+    weighted_rating: f64,
+    weighted_votes: f64,
+    normalized_rating: f64,
+    normalized_votes: u32,
+    geek_rating: f64,
+    avg_rating: f64,
+    bgg_votes: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<u32>
+}
+
+impl<'a> From<&Ranked<'a>> for Row<'a> {
+    fn from(r: &Ranked<'a>) -> Row<'a> {
+        let game = r.game;
+        Row {
+            rank: r.rank,
+            bgg_rank: r.bgg_rank,
+            shift: r.shift,
+            id: game.id,
+            name: &game.name,
+            rating: game.rating,
+            bayes: game.bayes,
+            votes: game.votes,
+            weighted_rating: game.weighted_rating,
+            weighted_votes: game.weighted_votes,
+            normalized_rating: game.normalized_rating,
+            normalized_votes: game.normalized_votes,
+            geek_rating: game.bgg_geek_rating,
+            avg_rating: game.bgg_avg_rating,
+            bgg_votes: game.bgg_num_votes,
+            page: r.page
+        }
+    }
+}
+
+fn or_dash<T: Display>(value: Option<T>) -> String {
+    match value {
+        Some(v) => v.to_string(),
+        None => String::from("-")
+    }
+}
+
+fn cells(r: &Ranked, partial: bool) -> Vec<String> {
+    let game = r.game;
+    let mut cells = vec![
+        or_dash(r.rank),
+        r.bgg_rank.to_string(),
+        or_dash(r.shift.map(|s| format!("{:+}", s))),
+        game.id.to_string(),
+        game.name.clone(),
+        format!("{:.2}", game.rating),
+        format!("{:.2}", game.bayes),
+        game.votes.to_string(),
+        format!("{:.2}", game.weighted_rating),
+        format!("{:.2}", game.weighted_votes),
+        format!("{:.2}", game.normalized_rating),
+        game.normalized_votes.to_string(),
+        game.bgg_geek_rating.to_string(),
+        game.bgg_avg_rating.to_string(),
+        game.bgg_num_votes.to_string()
+    ];
+    if partial {
+        cells.push(match r.page {
+            Some(page) => format!("pending, page {}", page),
+            None => String::from("balanced")
+        });
+    }
+    cells
+}
+
+fn header(partial: bool) -> Vec<&'static str> {
+    let mut header = HEADER.to_vec();
+    if partial {
+        header.push("Status");
+    }
+    header
+}
+
+/// pending is Some for partial report, games in progress are listed
+/// after balanced ones with their state.
+pub fn render(games: &[Game], pending: Option<&[Pending]>, format: Format, score: Score, sort: Sort) -> String {
+    let partial = pending.is_some();
+    let games = rank(games, pending.unwrap_or(&[]), score, sort);
+    let games = &games[..];
+    match format {
+        Format::Tsv => table(games, partial, |c| c.replace(&['\t', '\n', '\r'][..], " "),
+            |row| format!("{}\n", row.join("\t"))),
+        Format::Csv => table(games, partial, csv_escape, |row| format!("{}\r\n", row.join(","))),
+        Format::Markdown => markdown(games, partial),
+        Format::Html => html(games, partial),
+        Format::Json => {
+            let rows: Vec<Row> = games.iter().map(Row::from).collect();
+            // plain structs, serialization can't fail
+            let mut json = to_string_pretty(&rows).unwrap();
+            json.push('\n');
+            json
+        }
+    }
+}
+
+fn table(games: &[Ranked], partial: bool, escape: impl Fn(&str) -> String, line: impl Fn(Vec<String>) -> String) -> String {
+    let mut text = line(header(partial).iter().map(|h| escape(h)).collect());
+    for game in games {
+        text.push_str(&line(cells(game, partial).iter().map(|c| escape(c)).collect()));
+    }
+    text
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn markdown_escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' | '|' | '*' | '_' | '`' | '[' | ']' | '<' | '>' => {
+                escaped.push('\\');
+                escaped.push(c);
+            },
+            '\n' | '\r' | '\t' => escaped.push(' '),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn markdown(games: &[Ranked], partial: bool) -> String {
+    let row = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+    let header = header(partial);
+    let mut text = row(header.iter().map(|h| h.to_string()).collect());
+    // everything but the name and status is a number
+    let align: Vec<String> = header.iter()
+        .map(|h| if *h == "Name" || *h == "Status" { String::from("---") } else { String::from("---:") })
+        .collect();
+    text.push_str(&row(align));
+    for game in games {
+        text.push_str(&row(cells(game, partial).iter().map(|c| markdown_escape(c)).collect()));
+    }
+    text
+}
+
+fn html_escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+fn html(games: &[Ranked], partial: bool) -> String {
+    let mut text = String::from("<table>\n<thead>\n<tr>");
+    for h in header(partial) {
+        text.push_str(&format!("<th>{}</th>", html_escape(h)));
+    }
+    text.push_str("</tr>\n</thead>\n<tbody>\n");
+    for game in games {
+        text.push_str("<tr>");
+        for c in cells(game, partial) {
+            text.push_str(&format!("<td>{}</td>", html_escape(&c)));
+        }
+        text.push_str("</tr>\n");
+    }
+    text.push_str("</tbody>\n</table>\n");
+    text
+}
+
+/// Place and score of a game in two reports, None where it is absent.
+pub struct Change {
+    pub name: String,
+    pub before: Option<(u32, f64)>,
+    pub after: Option<(u32, f64)>
+}
+
+impl Change {
+    /// Positive if game has climbed.
+    pub fn shift(&self) -> Option<i64> {
+        match (self.before, self.after) {
+            (Some((b, _)), Some((a, _))) => Some(i64::from(b) - i64::from(a)),
+            _ => None
+        }
+    }
+}
+
+/// Every game of both reports ranked by score, games in the newer
+/// report come first in its order, then the ones that left.
+pub fn compare(old: &[Game], new: &[Game], score: Score) -> Vec<Change> {
+    let placed = |games: &[Game]| -> HashMap<u32, (u32, f64)> {
+        let scores: Vec<f64> = games.iter().map(|g| score.of(g)).collect();
+        games.iter().zip(places(&scores)).zip(scores.iter())
+            .map(|((game, place), &s)| (game.id, (place, s)))
+            .collect()
+    };
+    let (before, after) = (placed(old), placed(new));
+    let mut changes: Vec<Change> = new.iter()
+        .map(|game| Change { name: game.name.clone(), before: before.get(&game.id).cloned(), after: after.get(&game.id).cloned() })
+        .chain(old.iter()
+            .filter(|game| !after.contains_key(&game.id))
+            .map(|game| Change { name: game.name.clone(), before: before.get(&game.id).cloned(), after: None }))
+        .collect();
+    changes.sort_by_key(|c| (c.after.is_none(), c.after.map(|(place, _)| place), c.before.map(|(place, _)| place)));
+    changes
+}
@@ -0,0 +1,256 @@
+use rusqlite::{Connection, NO_PARAMS, TransactionBehavior};
+use rusqlite::types::ToSql;
+use crate::error::Error;
+
+/// Ordered changes of top.db, position in the list + 1 is the version
+/// they bring db to. Never edit applied ones, add a new one instead.
+const MIGRATIONS: [(&str, &str); 13] = [
+    ("create games and users", "
+        create table games (
+            id integer primary key,
+            name text not null,
+            rating real,
+            num_votes integer,
+            updated datetime,
+            stable integer,
+            bgg_num_votes integer,
+            bgg_geek_rating real,
+            bgg_avg_rating real,
+            page integer,
+            temp_n integer,
+            temp_r real
+        );
+        create table users (
+            name text primary key,
+            updated datetime,
+            stable integer,
+            trusted integer
+        );"),
+    // bayes depends on rating and votes only, default config is used here
+    // and recompute redoes it with the actual one
+    ("add bayes score", "
+        alter table games add column bayes real;
+        update games set bayes = (1500 * 5.5 + num_votes * rating) / (1500 + num_votes)
+            where stable and rating is not null and num_votes is not null;"),
+    ("store trust policy", "
+        alter table users add column policy text;
+        alter table games add column policy text;"),
+    // balanced games stay so without the new scores, they are legacy until
+    // review sends them back, users get their stats when review expires them
+    ("add weighted score", "
+        alter table users add column average real;
+        alter table games add column weighted_rating real;
+        alter table games add column weighted_votes real;"),
+    ("add normalized score", "
+        alter table users add column std_dev real;
+        alter table games add column normalized_rating real;
+        alter table games add column normalized_votes integer;"),
+    // running averages are gone, unfinished games start over to save their ratings
+    ("add ratings table", "
+        create table ratings (
+            game_id integer,
+            user text,
+            rating real,
+            comment text,
+            fetched_at datetime,
+            primary key (game_id, user)
+        );
+        update games set page = 1 where not stable;"),
+    // users get their counts when review expires them
+    ("store rating counts", "
+        alter table users add column ratings integer;"),
+    ("drop running averages", "
+        create table games_new (
+            id integer primary key,
+            name text not null,
+            rating real,
+            bayes real,
+            num_votes integer,
+            weighted_rating real,
+            weighted_votes real,
+            normalized_rating real,
+            normalized_votes integer,
+            updated datetime,
+            stable integer,
+            bgg_num_votes integer,
+            bgg_geek_rating real,
+            bgg_avg_rating real,
+            page integer,
+            policy text
+        );
+        insert into games_new (id, name, rating, bayes, num_votes, weighted_rating, weighted_votes,
+            normalized_rating, normalized_votes, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating,
+            page, policy)
+        select id, name, rating, bayes, num_votes, weighted_rating, weighted_votes,
+            normalized_rating, normalized_votes, updated, stable, bgg_num_votes, bgg_geek_rating, bgg_avg_rating,
+            page, policy from games;
+        drop table games;
+        alter table games_new rename to games;"),
+    ("flag dropped games", "
+        alter table games add column dropped integer not null default 0;"),
+    ("add pull staging", "
+        create table staged_games (
+            id integer primary key,
+            name text not null,
+            bgg_num_votes integer,
+            bgg_geek_rating real,
+            bgg_avg_rating real,
+            page integer,
+            position integer
+        );
+        create table pull_state (
+            user_limit integer not null,
+            page integer not null
+        );"),
+    ("add snapshots", "
+        create table snapshots (
+            id integer primary key,
+            label text unique,
+            taken datetime not null,
+            config text not null
+        );
+        create table snapshot_games (
+            snapshot_id integer not null,
+            game_id integer not null,
+            name text not null,
+            rating real,
+            bayes real,
+            num_votes integer,
+            weighted_rating real,
+            weighted_votes real,
+            normalized_rating real,
+            normalized_votes integer,
+            bgg_num_votes integer,
+            bgg_geek_rating real,
+            bgg_avg_rating real,
+            primary key (snapshot_id, game_id)
+        );"),
+    ("add user claims", "
+        alter table users add column claimed_by integer;"),
+    // reviewed games keep their saved ratings and wait for their users only
+    ("add rescore flag", "
+        alter table games add column rescore integer not null default 0;")
+];
+
+/// Version a migration brings db to and its name.
+pub type Migration = (u32, &'static str);
+
+pub fn latest() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Version of db, files made before versioning are told by the latest
+/// column or table they got when opened.
+pub fn version(conn: &Connection) -> Result<u32, Error> {
+    let exists = |table: &str| -> Result<bool, Error> {
+        let mut stmt = conn.prepare("select count(*) from sqlite_master where type = 'table' and name = ?1")?;
+        let count: u32 = stmt.query_row(&[&table as &dyn ToSql], |r| r.get(0))?;
+        Ok(count > 0)
+    };
+    let has_column = |table: &str, column: &str| -> Result<bool, Error> {
+        let mut stmt = conn.prepare(&format!("pragma table_info({})", table))?;
+        let columns = stmt.query_map(NO_PARAMS, |r| r.get::<_, String>(1))?;
+        for name in columns {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    if exists("schema_version")? {
+        let mut stmt = conn.prepare("select version from schema_version")?;
+        let version: u32 = stmt.query_row(NO_PARAMS, |r| r.get(0))?;
+        Ok(version)
+    } else if !exists("games")? {
+        Ok(0)
+    } else if has_column("users", "ratings")? {
+        Ok(7)
+    } else if exists("ratings")? {
+        Ok(6)
+    } else if has_column("users", "std_dev")? {
+        Ok(5)
+    } else if has_column("users", "average")? {
+        Ok(4)
+    } else if has_column("games", "policy")? {
+        Ok(3)
+    } else if has_column("games", "bayes")? {
+        Ok(2)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Migrations that are not applied to db yet, with their versions.
+pub fn pending(conn: &Connection) -> Result<Vec<Migration>, Error> {
+    let version = version(conn)?;
+    Ok(MIGRATIONS.iter().enumerate()
+        .map(|(i, (name, _))| (i as u32 + 1, *name))
+        .filter(|(v, _)| *v > version)
+        .collect())
+}
+
+/// Brings db to the latest version, returns applied migrations.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<Migration>, Error> {
+    let version = version(conn)?;
+    if version > latest() {
+        return Err(Error::Schema(format!("db has version {}, the latest known is {}", version, latest())));
+    }
+    if version == latest() {
+        return Ok(Vec::new());
+    }
+    // immediate, so another connection can't migrate at the same time
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let applied = pending(&tx)?;
+    for (v, _) in &applied {
+        tx.execute_batch(MIGRATIONS[*v as usize - 1].1)?;
+    }
+    tx.execute_batch("create table if not exists schema_version (version integer not null);
+        delete from schema_version;")?;
+    tx.execute("insert into schema_version (version) values (?1)", &[&latest() as &dyn ToSql])?;
+    tx.commit()?;
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // db as the first version made it, with one balanced game and user
+    fn baseline() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0].1).unwrap();
+        conn.execute_batch("
+            insert into games (id, name, rating, num_votes, updated, stable, page, temp_n, temp_r)
+                values (1, 'One', 7.5, 10, '2020-01-01', 1, 3, 0, 0);
+            insert into games (id, name, rating, num_votes, updated, stable, page, temp_n, temp_r)
+                values (2, 'Two', null, null, null, 0, 2, 5, 6.0);
+            insert into users (name, updated, stable, trusted) values ('a', '2020-01-01', 1, 1);").unwrap();
+        conn
+    }
+
+    #[test]
+    fn infers_baseline_version() {
+        assert_eq!(version(&baseline()).unwrap(), 1);
+    }
+
+    #[test]
+    fn keeps_balanced_games() {
+        let mut conn = baseline();
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied.len() as u32, latest() - 1);
+        assert_eq!(version(&conn).unwrap(), latest());
+        let (stable, rating, votes, bayes): (bool, f64, u32, f64) = conn.query_row(
+            "select stable, rating, num_votes, bayes from games where id = 1", NO_PARAMS,
+            |r| (r.get(0), r.get(1), r.get(2), r.get(3))).unwrap();
+        assert!(stable);
+        assert_eq!((rating, votes), (7.5, 10));
+        assert!((bayes - (1500.0 * 5.5 + 10.0 * 7.5) / 1510.0).abs() < 1e-9);
+        // unfinished game starts over to save its ratings
+        let (stable, page): (bool, u32) = conn.query_row("select stable, page from games where id = 2", NO_PARAMS,
+            |r| (r.get(0), r.get(1))).unwrap();
+        assert_eq!((stable, page), (false, 1));
+        let (stable, trusted): (bool, bool) = conn.query_row("select stable, trusted from users where name = 'a'", NO_PARAMS,
+            |r| (r.get(0), r.get(1))).unwrap();
+        assert!(stable && trusted);
+    }
+}
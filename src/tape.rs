@@ -0,0 +1,54 @@
+use serde_derive::{Serialize, Deserialize};
+use serde_json::{from_str, to_string_pretty};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::Error;
+
+/// Where bgg responses come from.
+#[derive(Debug, Clone)]
+pub enum Tape {
+    Off, // network only
+    Record(PathBuf), // network, every response is saved to cassette dir
+    Replay(PathBuf) // cassette dir only, no network access
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cassette {
+    pub url: String, // relative to base url, so cassettes survive mirror change
+    pub status: u16,
+    pub body: String
+}
+
+pub fn record(dir: &Path, cassette: &Cassette) -> Result<(), Error> {
+    fs::create_dir_all(dir)
+        .map_err(|e| Error::io(dir.display(), e))?;
+    let file = dir.join(file_name(&cassette.url));
+    let content = to_string_pretty(cassette)
+        .map_err(|e| Error::Cassette(format!("Can't save cassette for `{}`: {}", cassette.url, e)))?;
+    fs::write(&file, content)
+        .map_err(|e| Error::io(file.display(), e))?;
+    Ok(())
+}
+
+pub fn replay(dir: &Path, url: &str) -> Result<Cassette, Error> {
+    let file = dir.join(file_name(url));
+    let cassette = match fs::read_to_string(&file) {
+        Ok(c) => c,
+        Err(_) => return Err(Error::Cassette(format!("No cassette for `{}` in {}", url, dir.display())))
+    };
+    let cassette: Cassette = from_str(&cassette)
+        .map_err(|e| Error::Cassette(format!("Can't read cassette {}: {}", file.display(), e)))?;
+    if cassette.url != url {
+        return Err(Error::Cassette(
+            format!("Cassette {} belongs to `{}`, not `{}`", file.display(), cassette.url, url)));
+    }
+    Ok(cassette)
+}
+
+// FNV-1a, stable between runs and rust versions unlike DefaultHasher
+fn file_name(url: &str) -> String {
+    let hash = url.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}.json", hash)
+}
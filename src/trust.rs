@@ -0,0 +1,77 @@
+use serde_derive::{Serialize, Deserialize};
+use crate::error::Error;
+use crate::lib::UserStats;
+
+/// Named set of rules, user is trusted only if every rule passes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct TrustPolicy {
+    pub name: String, // stored with users and games judged by the policy
+    pub rules: Vec<Rule>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Rule {
+    Bounds { lower: f64, upper: f64 }, // average rating strictly inside
+    MinRatings { min: u32 }, // at least that many rated games, unknown number fails
+    Percentile { lower: f64, upper: f64 } // average inside percentiles of already judged users, 0..100
+}
+
+impl TrustPolicy {
+    /// percentile tells the share of judged users with lower average,
+    /// None if there is nobody to compare with.
+    pub fn trusts(&self, stats: &UserStats, percentile: impl Fn(f64) -> Result<Option<f64>, Error>) -> Result<bool, Error> {
+        if stats.is_empty() {
+            return Ok(false);
+        }
+        for rule in &self.rules {
+            if !rule.passes(stats, &percentile)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Rules that can never pass or make no sense.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.is_empty() {
+            problems.push(String::from("trust.name must not be empty"));
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            match *rule {
+                Rule::Bounds { lower, upper } if lower >= upper =>
+                    problems.push(format!("trust.rules.{}: lower bound {} must be below upper {}", i, lower, upper)),
+                Rule::Percentile { lower, upper } if !(0.0 <= lower && lower <= upper && upper <= 100.0) =>
+                    problems.push(format!("trust.rules.{}: percentiles must be within 0..100, lower first, got {}..{}", i, lower, upper)),
+                _ => {}
+            }
+        }
+        problems
+    }
+}
+
+impl Rule {
+    fn passes(self, stats: &UserStats, percentile: &impl Fn(f64) -> Result<Option<f64>, Error>) -> Result<bool, Error> {
+        let passes = match self {
+            Rule::Bounds { lower, upper } => lower < stats.average && stats.average < upper,
+            Rule::MinRatings { min } => stats.ratings.unwrap_or(0) >= min,
+            // nobody to compare with yet, let the first users in
+            Rule::Percentile { lower, upper } => match percentile(stats.average)? {
+                None => true,
+                Some(p) => lower <= p && p <= upper
+            }
+        };
+        Ok(passes)
+    }
+}
+
+impl Default for TrustPolicy {
+    fn default() -> TrustPolicy {
+        TrustPolicy {
+            name: String::from("bounds"),
+            rules: vec![Rule::Bounds { lower: 2.0, upper: 8.0 }]
+        }
+    }
+}